@@ -1,40 +1,333 @@
-mod utils;
-
 // use utils::grammar::*;
-// use utils::nfa::*;
 // use utils::state_utils::*;
-use utils::boyer_moore::*;
 use std::env;
+use utils::cli::*;
+use utils::output::*;
+use utils::json::*;
 use utils::regex::*;
-use utils::earley_parse::*;
+use utils::search::*;
+use utils::walk::*;
+use utils::nfa::NfaConfig;
 use std::fs;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::path::Path;
+use std::process;
 
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let regex = &args[1];
-    let filename = &args[2];
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(Command::Search(options)) => options,
+        Ok(Command::Help) => {
+            println!("{}\n\n{}", USAGE, HELP);
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("grep (rust_grep) {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(e) => {
+            eprintln!("grep: {}\n{}\nTry 'grep --help' for more information.", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let patterns = match read_patterns(&options) {
+        Ok(patterns) => patterns,
+        Err((filename, e)) => {
+            eprintln!("grep: {}: {}", filename, e);
+            process::exit(2);
+        }
+    };
+    // An empty pattern file leaves nothing to match
+    let compiled = match patterns.is_empty() {
+        true => None,
+        false => match compile_patterns(&patterns, &nfa_config(&options)) {
+            Ok(regex) => Some(regex),
+            Err((pattern, e)) => {
+                eprintln!("{}", e.diagnostic(&pattern));
+                process::exit(2);
+            }
+        },
+    };
+    if options.stats {
+        if let Some(regex) = &compiled {
+            println!("{}", regex.stats());
+        }
+        return;
+    }
+
+    // One automaton serves every file
+    let searcher = Searcher::new(compiled.as_ref(), &options);
+    // Without a file, -r searches the working directory, naming files
+    // relative to it, and otherwise standard input is read
+    let implicit = options.files.is_empty() && options.recursive;
+    let operands = match options.files.is_empty() {
+        true if implicit => vec![".".to_string()],
+        true => vec!["-".to_string()],
+        false => options.files.clone(),
+    };
+    let show_names = options.with_filename.unwrap_or(options.recursive || operands.len() > 1);
+    let colored = match options.color {
+        ColorChoice::Always => true,
+        ColorChoice::Auto => io::stdout().is_terminal() && env::var("TERM").is_ok_and(|term| term != "dumb"),
+        ColorChoice::Never => false,
+    };
+    let colors = match colored {
+        true => Colors::parse(&env::var("GREP_COLORS").unwrap_or_default()),
+        false => Colors::none(),
+    };
+    let mut printer = Printer::new(BufWriter::new(io::stdout().lock()), &options, show_names).with_colors(colors);
+    // Whether any line was selected and whether any file failed, which
+    // make the exit status
+    let mut selected = false;
+    let mut failed = false;
+    for entry in Walker::new(&operands, options.recursive, options.follow_symlinks, &options.include, &options.exclude) {
+        // Warnings name the path the same way matches do
+        let name = |path: &Path| match implicit {
+            _ if path.as_os_str() == "-" => "(standard input)".to_string(),
+            true => path.strip_prefix(".").unwrap_or(path).display().to_string(),
+            false => path.display().to_string(),
+        };
+        let path = match entry {
+            Entry::File(path) => path,
+            Entry::Loop(path) => {
+                eprintln!("grep: {}: warning: recursive directory loop", name(&path));
+                continue;
+            }
+            Entry::Error(path, e) => {
+                eprintln!("grep: {}: {}", name(&path), e);
+                failed = true;
+                continue;
+            }
+        };
+        let name = name(&path);
+        let reader = match open_input(&path) {
+            Ok(reader) => reader,
+            Err(e) => {
+                eprintln!("grep: {}: {}", name, e);
+                failed = true;
+                continue;
+            }
+        };
+        match print_results(&mut printer, &options, &searcher, &name, reader) {
+            // With -q one selected line settles it, errors or not
+            Ok(true) if options.quiet => process::exit(0),
+            Ok(found) => selected |= found,
+            Err(Failure::Read(e)) => {
+                eprintln!("grep: {}: {}", name, e);
+                failed = true;
+            }
+            // The reader went away, as with `grep ... | head`
+            Err(Failure::Write(e, found)) if e.kind() == io::ErrorKind::BrokenPipe => {
+                process::exit(exit_status(selected || found, failed));
+            }
+            Err(Failure::Write(e, _)) => {
+                eprintln!("grep: {}", e);
+                process::exit(2);
+            }
+        }
+    }
+    if let Err(e) = printer.flush() {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("grep: {}", e);
+            failed = true;
+        }
+    }
+    process::exit(exit_status(selected, failed));
+}
+
+// How the command line says to compile patterns
+pub fn nfa_config(options: &Options) -> NfaConfig {
+    return NfaConfig {
+        case_insensitive: options.ignore_case,
+        multiline: options.multiline,
+        posix_classes: options.posix_classes,
+        ..NfaConfig::default()
+    };
+}
 
-    let contents = fs::read_to_string(filename).expect("File Not Found");
-    let matching_str = get_match(regex, &contents.to_string());
-    for m in matching_str{
-        println!("{}", m);
+// The patterns given with -e or as the first operand, then those of each -f
+// file, one per line. A blank line is an empty pattern, which matches every
+// line. An error comes with the file it was reading.
+pub fn read_patterns(options: &Options) -> Result<Vec<String>, (String, io::Error)> {
+    let mut patterns = options.patterns.clone();
+    for filename in options.pattern_files.iter() {
+        let contents = fs::read_to_string(filename).map_err(|e| (filename.clone(), e))?;
+        patterns.extend(contents.lines().map(String::from));
     }
+    return Ok(patterns);
+}
+
+// grep's exit status: 2 after an error, even if lines were selected, and
+// otherwise 0 if any line was selected and 1 if none was
+pub fn exit_status(selected: bool, failed: bool) -> i32 {
+    if failed {
+        return 2;
+    }
+    return if selected { 0 } else { 1 };
+}
+
+// Opens a file, or standard input for `-`, to be read a chunk at a time
+pub fn open_input(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    if path.as_os_str() == "-" {
+        return Ok(Box::new(BufReader::with_capacity(CHUNK_SIZE, io::stdin().lock())));
+    }
+    return Ok(Box::new(BufReader::with_capacity(CHUNK_SIZE, File::open(path)?)));
+}
+
+// Why an input could not be searched to the end: reading it failed, which
+// only affects that input, or writing the results did, along with whether
+// the input had a selected line by then
+#[derive(Debug)]
+pub enum Failure {
+    Read(io::Error),
+    Write(io::Error, bool),
+}
+
+// Searches one input and prints its selected lines as they are found, or
+// their count or the input's name once it is done. -q, -l and -L stop at the
+// first selected line. Returns whether any line was selected.
+pub fn print_results<R: BufRead, W: Write>(printer: &mut Printer<W>, options: &Options, searcher: &Searcher, filename: &str, reader: R) -> Result<bool, Failure> {
+    if options.json {
+        return print_events(printer, searcher, filename, reader);
+    }
+    let first_only = options.quiet || options.files_with_matches || options.files_without_match;
+    let print_lines = !first_only && !options.count;
+    let mut written = Ok(());
+    let count = searcher.search_chunks(reader, |line| {
+        match line {
+            Some(line) if print_lines => written = printer.line(filename, line),
+            Some(_) => {}
+            // Show the lines so far before waiting for more input
+            None => written = printer.flush(),
+        }
+        return written.is_ok() && !first_only;
+    }).map_err(Failure::Read)?;
+    written.map_err(|e| Failure::Write(e, count > 0))?;
+    let printed = if options.quiet {
+        Ok(())
+    }
+    else if options.files_with_matches {
+        if count > 0 { printer.path(filename) } else { Ok(()) }
+    }
+    else if options.files_without_match {
+        if count == 0 { printer.path(filename) } else { Ok(()) }
+    }
+    else if options.count {
+        printer.count(filename, count)
+    }
+    else {
+        Ok(())
+    };
+    printed.map_err(|e| Failure::Write(e, count > 0))?;
+    return Ok(count > 0);
+}
+
+// Prints the lines of one input as JSON Lines events, between a begin and an
+// end event if there are any
+pub fn print_events<R: BufRead, W: Write>(printer: &mut Printer<W>, searcher: &Searcher, filename: &str, reader: R) -> Result<bool, Failure> {
+    let mut stats = FileStats::default();
+    let mut begun = false;
+    let mut written = Ok(());
+    let count = searcher.search_chunks(reader, |line| {
+        let line = match line {
+            Some(line) => line,
+            None => {
+                written = printer.flush();
+                return written.is_ok();
+            }
+        };
+        if !begun {
+            begun = true;
+            written = printer.event(&Event::Begin { path: filename.to_string() });
+        }
+        let event = Event::from_line(filename, line, searcher.regex());
+        if let Event::Match { submatches, .. } = &event {
+            stats.matched_lines += 1;
+            stats.matches += submatches.len();
+        }
+        if written.is_ok() {
+            written = printer.event(&event);
+        }
+        return written.is_ok();
+    }).map_err(Failure::Read)?;
+    written.map_err(|e| Failure::Write(e, count > 0))?;
+    if begun {
+        printer.event(&Event::End { path: filename.to_string(), stats }).map_err(|e| Failure::Write(e, count > 0))?;
+    }
+    return Ok(count > 0);
 }
 
 pub fn run_cases(cases: Vec<(&str, &str, bool)>){
     for (regex, to_match, compare) in cases{
         let matching_str = get_match(regex, to_match);
         for m in &matching_str{
-            println!("{}", m);
+            println!("{:?}", m);
         }
-        assert!(!matching_str.is_empty() == compare);
+        assert!(matching_str.is_empty() != compare);
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use utils::boyer_moore::*;
+    use utils::dfa::*;
+    use utils::earley_parse::*;
+    use utils::error::*;
+    use utils::grammar::*;
+    use utils::nfa::*;
+    use utils::state_utils::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    // Each match as `line:text`, what `grep -no` prints
+    fn numbered_matches(regex: &str, to_match: &str) -> Vec<String> {
+        let options = Options { line_number: true, only_matching: true, ..Options::default() };
+        let mut printer = Printer::new(Vec::new(), &options, false);
+        for found in get_match(regex, to_match).iter() {
+            printer.line("", found).unwrap();
+        }
+        let out = String::from_utf8(printer.into_inner()).unwrap();
+        return out.lines().map(String::from).collect();
+    }
+
+    // The options of a command line, which has to be a search
+    fn search_options(args: &[&str]) -> Options {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        match parse_args(&args) {
+            Ok(Command::Search(options)) => return options,
+            other => panic!("{:?}: {:?}", args, other),
+        }
+    }
+
+    // Searches `input` as `options` say, with the patterns compiled the way
+    // main does, printing to `printer` under the name `log`
+    fn grep_with<W: Write>(options: &Options, printer: &mut Printer<W>, input: &[u8]) -> Result<bool, Failure> {
+        let regex = compile_patterns(&read_patterns(options).unwrap(), &nfa_config(options)).unwrap();
+        let searcher = Searcher::new(Some(&regex), options);
+        return print_results(printer, options, &searcher, "log", input);
+    }
+
+    // What grep prints for the command line `args` given `input`
+    fn grep(args: &[&str], input: &[u8]) -> Vec<u8> {
+        let options = search_options(args);
+        let mut printer = Printer::new(Vec::new(), &options, false);
+        grep_with(&options, &mut printer, input).unwrap();
+        return printer.into_inner();
+    }
+
+    fn grep_text(args: &[&str], input: &str) -> String {
+        return String::from_utf8(grep(args, input.as_bytes())).unwrap();
+    }
+
+    fn grep_lines(args: &[&str], input: &str) -> Vec<String> {
+        return grep_text(args, input).lines().map(String::from).collect();
+    }
+
     #[test]
     fn test_arith() {
         let mut g = CFG::new("EXP");
@@ -59,7 +352,8 @@ mod test {
     #[test]
     fn test_ours() {
         let mut cases: Vec<(&str, &str, bool)> = Vec::new();
-        cases.push(("\\D?", "123", false));
+        // the empty match before the first digit selects the line
+        cases.push(("\\D?", "123", true));
         run_cases(cases);
     }
 
@@ -85,17 +379,20 @@ mod test {
     fn test_slashes(){
         let mut cases: Vec<(&str, &str, bool)> = Vec::new();
         cases.push(("\\s", "123", false));
-        cases.push(("\\w", "123", false));
+        cases.push(("\\w", "123", true));
         cases.push(("\\d", "123", true));
 
-        cases.push(("\\s", "abc", true));
-        cases.push(("\\w", "abc", false));
+        cases.push(("\\s", "abc", false));
+        cases.push(("\\w", "abc", true));
         cases.push(("\\d", "abc", false));
 
-        cases.push(("\\s", "   ", false));
-        cases.push(("\\w", "   ", true));
+        cases.push(("\\s", "   ", true));
+        cases.push(("\\w", "   ", false));
         cases.push(("\\d", "   ", false));
 
+        cases.push(("\\s", "\r\x0b\x0c", true));
+        cases.push(("\\w", "_", true));
+
         run_cases(cases);
     }
 
@@ -116,12 +413,12 @@ mod test {
         cases.push(("\\D", "   ", true));
 
         cases.push(("\\S", "123", true));
-        cases.push(("\\S", "abc", false));
-        cases.push(("\\S", "   ", true));
+        cases.push(("\\S", "abc", true));
+        cases.push(("\\S", " \t\r", false));
 
-        cases.push(("\\W", "123", true));
-        cases.push(("\\W", "abc", true));
-        cases.push(("\\W", "   ", false));
+        cases.push(("\\W", "1_3", false));
+        cases.push(("\\W", "abc", false));
+        cases.push(("\\W", "   ", true));
 
         run_cases(cases);
     }
@@ -131,8 +428,9 @@ mod test {
         let mut cases: Vec<(&str, &str, bool)> = Vec::new();
         cases.push(("Python|Perl", "Perl", true));
         cases.push(("(Python|Perl)", "Perl", true));
-        cases.push(("(\\s\\d)|(\\s\\s)", "a1", true));
-        cases.push(("(\\s\\d)|(\\s\\s)", "aa", true));
+        cases.push(("(\\w\\d)|(\\w\\w)", "a1", true));
+        cases.push(("(\\w\\d)|(\\w\\w)", "aa", true));
+        cases.push(("(\\s\\d)|(\\s\\s)", "aa", false));
 
         run_cases(cases);
     }
@@ -152,6 +450,20 @@ mod test {
     pub fn test_boyer(){
         let idxs = string_search("Hello I am Bobby Daigle. ABABABAB", &"Bobby".to_string());
         println!("{:?}", idxs);
+        assert_eq!(idxs, vec![16]);
+        assert_eq!(string_search("Uber strabe", &"stra".to_string()), vec![9]);
+        assert_eq!(string_search("ABABABAB", &"ABAB".to_string()), vec![4, 6, 8]);
+        assert_eq!(string_search("Über straße, straße", &"straße".to_string()), vec![13, 22]);
+
+        // agrees with a naive search on every pattern over a small alphabet
+        let text = "abaabbbaababbabaaabbbabababbaaababbbbaabab";
+        for len in 1..6 {
+            for n in 0..(1 << len) {
+                let pattern: String = (0..len).map(|b| if n >> b & 1 == 1 { 'b' } else { 'a' }).collect();
+                let naive: Vec<usize> = (pattern.len()..=text.len()).filter(|&end| text[..end].ends_with(&pattern)).collect();
+                assert_eq!(string_search(text, &pattern), naive, "{}", pattern);
+            }
+        }
     }
 
     #[test]
@@ -174,8 +486,1019 @@ mod test {
         let mut cases: Vec<(&str, &str, bool)> = Vec::new();
         cases.push(("a?a?a?a?a?a?a?a?a?a?a?a?a?a?a?a?a?a?a?a?aaaaaaaaaaaaaaaaaaaa", "aaaaaaaaaaaaaaaaaaaa", true));
         cases.push(("a(1q?)|b2(abcq)?|3p+|3(abcp)+|4s*|4(abcs)*", "a\na1\na1q\nb\nb2\nb2abcq\n3\n3p3p\n3pp\n3abcpabcp\n4\n4s4s\n4ssss\n4abcsabcs", true));
-        cases.push(("	 !\"#$&'\\(\\)\\*\\+,-\\./0123456789:;<=>\\?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\\\]^_`abcdefghijklmnopqrstuvwxyz\\|", "	 !\"#$&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz|", true));
+        cases.push(("	 !\"#\\$&'\\(\\)\\*\\+,-\\./0123456789:;<=>\\?@ABCDEFGHIJKLMNOPQRSTUVWXYZ\\[\\\\]\\^_`abcdefghijklmnopqrstuvwxyz\\|", "	 !\"#$&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz|", true));
         cases.push(("\\W+ENDw|\\S+ENDs|\\D+ENDd", "	 	 ENDw\nABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyzENDs\n0123456789ENDd\nABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789ENDw\n	 	 0123456789ENDs\n	 	 ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyzENDd", true));
         run_cases(cases);
     }
+
+    #[test]
+    pub fn test_brackets() {
+        let mut cases: Vec<(&str, &str, bool)> = Vec::new();
+        cases.push(("[A-Fa-f0-9]+", "deadBEEF", true));
+        cases.push(("[A-Fa-f0-9]+", "xyz", false));
+        cases.push(("[a-z0-9_]", "___", true));
+        cases.push(("[.*]", "abc", false));
+        cases.push(("[.*]", "a*c", true));
+        cases.push(("[\\]\\-]", "]", true));
+        cases.push(("[\\]\\-]", "-", true));
+        cases.push(("[\\]\\-]", "a", false));
+        cases.push(("[-+]\\d", "+1", true));
+        cases.push(("[\\d.]+x", "1.5x", true));
+        cases.push(("[a^]", "^", true));
+        cases.push(("[\\s]+", "123", false));
+        run_cases(cases);
+    }
+
+    #[test]
+    pub fn test_neg_brackets() {
+        let mut cases: Vec<(&str, &str, bool)> = Vec::new();
+        cases.push(("[^a-z]", "abc", false));
+        cases.push(("[^a-z]", "abC", true));
+        cases.push(("[^\\d\\s]", "12 \t3", false));
+        cases.push(("[^\\d\\s]", "12 ab", true));
+        cases.push(("a[^-]c", "a-c", false));
+        cases.push(("a[^-]c", "abc", true));
+        run_cases(cases);
+    }
+
+    #[test]
+    pub fn test_anchors() {
+        let mut cases: Vec<(&str, &str, bool)> = Vec::new();
+        cases.push(("^abc", "abcdef", true));
+        cases.push(("^abc", "xabc", false));
+        cases.push(("^abc", "xyz\nabc", true));
+        cases.push(("abc$", "xyzabc", true));
+        cases.push(("abc$", "abcx", false));
+        cases.push(("abc$", "abc\nx", true));
+        cases.push(("^\\d+$", "123\n12a", true));
+        cases.push(("^\\d+$", "12a\na12", false));
+        cases.push(("\\Aabc", "xyz\nabc", false));
+        cases.push(("\\Aabc", "abc\nxyz", true));
+        cases.push(("abc\\z", "abc\nxyz", false));
+        cases.push(("abc\\z", "xyz\nabc", true));
+        cases.push(("a|^b", "ab", true));
+        cases.push(("\\^\\$", "^$", true));
+        run_cases(cases);
+    }
+
+    #[test]
+    pub fn test_prefix_anchored() {
+        assert_eq!(numbered_matches("ab|abcd", "abc"), vec!["1:ab"]);
+        assert_eq!(numbered_matches("a|ab", "xa"), vec!["1:a"]);
+        assert_eq!(numbered_matches("b.a", "abab\nbka\nab"), vec!["2:bka"]);
+        assert_eq!(numbered_matches("ab$", "abab\nabx"), vec!["1:ab"]);
+        assert_eq!(numbered_matches("ab$", "abx\nab"), vec!["2:ab"]);
+        assert!(numbered_matches("ab\\d", "xxab").is_empty());
+    }
+
+    #[test]
+    pub fn test_repeat() {
+        let mut cases: Vec<(&str, &str, bool)> = Vec::new();
+        cases.push(("\\d{4}-\\d{2}-\\d{2}", "released 2022-08-10", true));
+        cases.push(("\\d{4}-\\d{2}-\\d{2}", "released 22-08-10", false));
+        cases.push(("a{3}", "aa", false));
+        cases.push(("a{2,}", "a a", false));
+        cases.push(("a{2,}", "a aaaaa", true));
+        cases.push(("x{0}y", "y", true));
+        cases.push(("\\{\\}", "{}", true));
+        run_cases(cases);
+
+        assert_eq!(numbered_matches("a{2,}", "a aa aaaaa"), vec!["1:aa", "1:aaaaa"]);
+        assert_eq!(numbered_matches("a{0,2}b", "aaab"), vec!["1:aab"]);
+    }
+
+    #[test]
+    pub fn test_repeat_errors() {
+        let g = our_grammar();
+        let result = cfg2nfa(parse("a{100000}", &g).unwrap(), &NfaConfig::default());
+        assert_eq!(result.err().unwrap().kind, ErrorKind::RepeatTooLarge { limit: 5000 });
+        // a count too large to read reports the same limit
+        let result = cfg2nfa(parse("a{99999999999999999999}", &g).unwrap(), &NfaConfig::default());
+        assert_eq!(result.err(), Some(RegexError::new(ErrorKind::RepeatTooLarge { limit: 5000 }, 1)));
+
+        let config = NfaConfig { size_limit: 20, ..NfaConfig::default() };
+        assert!(cfg2nfa(parse("a{5}", &g).unwrap(), &config).is_ok());
+        assert!(cfg2nfa(parse("(a{5}){5}", &g).unwrap(), &config).is_err());
+
+        let result = cfg2nfa(parse("a{3,2}", &g).unwrap(), &config);
+        assert_eq!(result.err(), Some(RegexError::new(ErrorKind::InvalidRepeat { min: 3, max: 2 }, 1)));
+    }
+
+    #[test]
+    pub fn test_errors() {
+        let config = NfaConfig::default();
+        let err = compile("ab)c", &config).err().unwrap();
+        assert_eq!(err.offset, 2);
+        match &err.kind {
+            ErrorKind::Syntax { expected, .. } => {
+                assert!(expected.contains(&'|'));
+                assert!(!expected.contains(&')'));
+            }
+            _ => panic!("expected a syntax error"),
+        }
+
+        let err = compile("(ab", &config).err().unwrap();
+        assert_eq!(err.offset, 3);
+        assert_eq!(err.diagnostic("(ab"), "error: unexpected end of pattern at byte 3\n  (ab\n     ^\n  expected a literal character, ')', '*', '+', '?', '{' or '|'");
+
+        let err = compile("a{2x}", &config).err().unwrap();
+        assert_eq!(err.offset, 3);
+        assert_eq!(err.to_string(), "syntax error at byte 3, expected a digit, ',' or '}'");
+
+        // what is expected depends on where the parser is, not on which
+        // characters it would take
+        let err = compile("a\\", &config).err().unwrap();
+        assert_eq!(err.to_string(), "syntax error at byte 2, expected a character to escape");
+        let err = compile("[\\", &config).err().unwrap();
+        assert_eq!(err.to_string(), "syntax error at byte 2, expected a character to escape");
+        let err = compile("[^a", &config).err().unwrap();
+        assert_eq!(err.to_string(), "syntax error at byte 3, expected a literal character, '-' or ']'");
+
+        let err = compile("x[z-a]", &config).err().unwrap();
+        assert_eq!(err, RegexError::new(ErrorKind::InvalidRange { start: 'z', end: 'a' }, 2));
+
+        let err = compile("a{9999}", &config).err().unwrap();
+        assert_eq!(err.offset, 1);
+        assert!(err.diagnostic("a{9999}").contains("\n  a{9999}\n   ^"));
+    }
+
+    #[test]
+    pub fn test_regex_api() {
+        let re = Regex::new("[ab]{2,3}").unwrap();
+        let found: Vec<(usize, usize)> = re.find_iter("abbab xaab").map(|m| (m.start(), m.end())).collect();
+        assert_eq!(found, vec![(0, 3), (3, 5), (7, 10)]);
+        assert!(re.is_match("xxab"));
+        assert!(!re.is_match("a b a"));
+        assert_eq!(re.find("xbaab").map(|m| m.as_str()), Some("baa"));
+
+        // a shorter match that starts earlier wins over a longer one
+        let re = Regex::new("ab|b*c").unwrap();
+        let found: Vec<&str> = re.find_iter("abbbc").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["ab", "bbc"]);
+
+        // empty matches are never reported
+        let re = Regex::new("a*").unwrap();
+        let found: Vec<(usize, usize)> = re.find_iter("bab").map(|m| (m.start(), m.end())).collect();
+        assert_eq!(found, vec![(1, 2)]);
+
+        let re = Regex::new("ab\\d").unwrap();
+        assert_eq!(re.find_at("ab1 ab2", 1).map(|m| m.start()), Some(4));
+        let caps = re.captures("x ab7").unwrap();
+        assert_eq!(caps.len(), 1);
+        assert_eq!(caps.get(0).map(|m| m.as_str()), Some("ab7"));
+        assert!(caps.get(1).is_none());
+        assert!(re.captures("ab").is_none());
+    }
+
+    #[test]
+    pub fn test_captures() {
+        let re = Regex::new("(\\d{4})-(\\d{2})-(?P<day>\\d{2})").unwrap();
+        assert_eq!(re.captures_len(), 4);
+        let caps = re.captures("released 2022-08-10 at noon").unwrap();
+        assert_eq!(caps.get(0).map(|m| m.as_str()), Some("2022-08-10"));
+        assert_eq!(caps.get(1).map(|m| m.as_str()), Some("2022"));
+        assert_eq!(caps.get(2).map(|m| (m.start(), m.end())), Some((14, 16)));
+        assert_eq!(caps.name("day").map(|m| m.as_str()), Some("10"));
+        assert!(caps.name("month").is_none());
+
+        // groups are numbered by their opening parenthesis, (?:...) is skipped
+        let re = Regex::new("(?:x(a(b))|(?<other>c))+").unwrap();
+        let names: Vec<Option<&str>> = re.capture_names().collect();
+        assert_eq!(names, vec![None, None, None, Some("other")]);
+        let caps = re.captures("-xab-").unwrap();
+        assert_eq!(caps.get(1).map(|m| m.as_str()), Some("ab"));
+        assert_eq!(caps.get(2).map(|m| m.as_str()), Some("b"));
+        assert!(caps.get(3).is_none());
+
+        // copies made by counted repetition share a group, the last one wins
+        let caps = Regex::new("([ab]){3}").unwrap().captures("abb").unwrap();
+        assert_eq!(caps.len(), 2);
+        assert_eq!(caps.get(1).map(|m| m.as_str()), Some("b"));
+
+        // groups do not change what matches
+        let mut cases = Vec::new();
+        cases.push(("(ab)+wh", "ababwh", true));
+        cases.push(("(?:ab)+wh", "abawh", false));
+        cases.push(("(?P<w>a|b)c", "bc", true));
+        cases.push(("(?P<w>a|b)c", "cc", false));
+        run_cases(cases);
+
+        let err = compile("(?P<a>x)(?<a>y)", &NfaConfig::default()).err().unwrap();
+        assert_eq!(err, RegexError::new(ErrorKind::DuplicateGroupName { name: "a".to_string() }, 11));
+        assert!(Regex::new("(?P<1a>x)").is_err());
+        assert!(Regex::new("()").is_err());
+    }
+
+    #[test]
+    pub fn test_dfa() {
+        // the DFAs have to agree with the NFA simulation they stand in for
+        let patterns = ["ab|b*c", "a*", "[ab]{2,3}", "^ab", "b$", "\\Aa|c\\z", "(a|b)*c", "a.c", "x?y+", "\\d+ms", "^$a", "ab|abcd"];
+        let texts = ["abbbc", "bab\nab ab", "xaabcab\nabb", "a bc\ncab c", "yy xyxy", "took 12ms, 7ms\n3 ms", "abc", ""];
+        let lazy = NfaConfig { dfa_state_limit: 1, ..NfaConfig::default() };
+        let nfa_only = NfaConfig { dfa_state_limit: 1, dfa_cache_limit: 0, ..NfaConfig::default() };
+        for pattern in patterns.iter() {
+            let expected: Vec<Vec<(usize, usize)>> = texts.iter().map(|text| {
+                compile(pattern, &nfa_only).unwrap().find_iter(text).map(|m| (m.start(), m.end())).collect()
+            }).collect();
+            for config in [NfaConfig::default(), lazy.clone()] {
+                let re = compile(pattern, &config).unwrap();
+                for (text, expected) in texts.iter().zip(expected.iter()) {
+                    let found: Vec<(usize, usize)> = re.find_iter(text).map(|m| (m.start(), m.end())).collect();
+                    assert_eq!(&found, expected, "{} on {:?}", pattern, text);
+                }
+            }
+        }
+
+        // subset construction gives up past its budget
+        let g = our_grammar();
+        let (start, states, _) = cfg2nfa(parse("(a|b)*a(a|b)(a|b)(a|b)(a|b)(a|b)", &g).unwrap(), &NfaConfig::default()).unwrap();
+        assert!(Dfa::build(&states, start, false, 32).is_none());
+        let dfa = Dfa::build(&states, start, false, 1000).unwrap();
+        assert!(dfa.len() > 64);
+        assert_eq!(earliest_match_end(&dfa, "bbaabbab", 0), Ok(Some(8)));
+        assert_eq!(earliest_match_end(&dfa, "bbaab", 0), Ok(None));
+    }
+
+    #[test]
+    pub fn test_lazy_dfa() {
+        let g = our_grammar();
+        let (start, states, _) = cfg2nfa(parse("(a|b)*a(a|b)(a|b)(a|b)(a|b)(a|b)", &g).unwrap(), &NfaConfig::default()).unwrap();
+        let text = "ab".repeat(200) + "bbbbb";
+        let nfa: std::rc::Rc<[_]> = states.into();
+
+        // a roomy cache determinizes only the states the text reaches
+        let dfa = LazyDfa::new(nfa.clone(), start, false, 1 << 20);
+        assert_eq!(earliest_match_end(&dfa, &text, 0), Ok(Some(6)));
+        assert!(dfa.cached_states() < 16);
+        assert_eq!(earliest_match_end(&dfa, "bbbbbbbbbbbbabbbbb", 0), Ok(Some(18)));
+
+        // a small one is flushed as it fills, and the search still completes
+        let dfa = LazyDfa::new(nfa.clone(), start, false, 2048);
+        let text = "abbabaabbbaaabab".repeat(100) + "\nabbbbb";
+        assert_eq!(earliest_match_end(&dfa, &text[1600..], 0), Ok(Some(7)));
+        assert_eq!(earliest_match_end(&dfa, &text, 0), Ok(Some(6)));
+        assert!(!dfa.gave_up());
+
+        // one that cannot hold anything thrashes, and the DFA gives up
+        let dfa = LazyDfa::new(nfa, start, false, 0);
+        assert_eq!(earliest_match_end(&dfa, &text, 0), Err(GaveUp));
+        assert!(dfa.gave_up());
+        assert_eq!(dfa.start(Context::TextStart), Err(GaveUp));
+    }
+
+    #[test]
+    pub fn test_minimize() {
+        // the two branches end up in states no text can tell apart
+        let stats = Regex::new("xa|ya").unwrap().stats().clone();
+        assert_eq!(stats.nfa_states, 4);
+        assert_eq!(stats.dfa_states, Some((7, 5)));
+        assert_eq!(stats.minimized_states, Some((4, 4)));
+        assert_eq!(stats.to_string(), "NFA states: 4\nDFA states: 7 unanchored, 5 anchored\nminimized DFA states: 4 unanchored, 4 anchored");
+
+        let g = our_grammar();
+        let (start, states, _) = cfg2nfa(parse("(a|b)*abb", &g).unwrap(), &NfaConfig::default()).unwrap();
+        let dfa = Dfa::build(&states, start, false, 1000).unwrap();
+        let minimized = dfa.minimize();
+        assert!(minimized.len() < dfa.len());
+        assert_eq!(minimized.minimize().len(), minimized.len());
+        for text in ["abb", "babb", "ab", "aabbb", "abab\nabb"] {
+            assert_eq!(earliest_match_end(&minimized, text, 0), earliest_match_end(&dfa, text, 0));
+        }
+
+        let stats = compile("(a|b)*a(a|b)(a|b)", &NfaConfig { dfa_state_limit: 4, ..NfaConfig::default() }).unwrap().stats().clone();
+        assert_eq!(stats.dfa_states, None);
+        assert_eq!(stats.to_string().lines().last(), Some("DFA states: more than 4, using a lazy DFA"));
+    }
+
+    #[test]
+    pub fn test_ranges() {
+        assert_eq!(normalize_ranges(vec![('d', 'f'), ('a', 'b'), ('c', 'c'), ('x', 'z'), ('e', 'h')]), vec![('a', 'h'), ('x', 'z')]);
+        assert_eq!(negate_ranges(vec![('!', '~')]), vec![('\0', '\t'), ('\u{b}', ' '), ('\x7f', '\u{d7ff}'), ('\u{e000}', char::MAX)]);
+
+        // a whole class is a handful of range transitions, not one per character
+        let g = our_grammar();
+        let transitions = |pattern: &str| {
+            let (start, states, _) = cfg2nfa(parse(pattern, &g).unwrap(), &NfaConfig::default()).unwrap();
+            let mut seen = vec![start];
+            let mut count = 0;
+            while let Some(state) = seen.pop() {
+                for t in states[state].out.iter() {
+                    count += 1;
+                    if !seen.contains(&t.state.unwrap()) && t.state != Some(state) {
+                        seen.push(t.state.unwrap());
+                    }
+                }
+            }
+            count
+        };
+        assert_eq!(transitions("."), 3);
+        assert_eq!(transitions("[a-z0-9_]"), 3);
+        assert!(transitions("\\S+") <= 12);
+
+        let mut cases = Vec::new();
+        cases.push(("[^b-y]+", "abcyz", true));
+        cases.push(("[^a-z]", "abcyz", false));
+        cases.push(("[a-cx-z]{2}", "bx", true));
+        cases.push(("[a-cx-z]{2}", "dw", false));
+        cases.push(("\\D\\D", "a!", true));
+        run_cases(cases);
+    }
+
+    #[test]
+    pub fn test_unicode() {
+        // offsets are bytes into the text, and `.` consumes a whole character
+        let re = Regex::new("stra.e").unwrap();
+        let m = re.find("Über die straße").unwrap();
+        assert_eq!((m.start(), m.end(), m.as_str()), (10, 17, "straße"));
+
+        let re = Regex::new("日本.").unwrap();
+        let found: Vec<&str> = re.find_iter("日本語と日本人").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["日本語", "日本人"]);
+
+        let re = Regex::new("(é+)(\\d)").unwrap();
+        let caps = re.captures("café2 éé3").unwrap();
+        assert_eq!(caps.get(1).map(|m| (m.start(), m.as_str())), Some((3, "é")));
+        assert_eq!(caps.get(2).map(|m| m.as_str()), Some("2"));
+
+        // a negated class or `.` matches characters outside ASCII, even
+        // beyond the basic multilingual plane
+        let re = Regex::new("[^a-z ]+").unwrap();
+        assert_eq!(re.find("abc ÿø😀 x").map(|m| m.as_str()), Some("ÿø😀"));
+        assert_eq!(Regex::new("^.$").unwrap().find("😀").map(|m| m.end()), Some(4));
+
+        // the caret lines up with the character, not the byte
+        let err = compile("ñ)", &NfaConfig::default()).err().unwrap();
+        assert_eq!(err.offset, 2);
+        assert!(err.diagnostic("ñ)").starts_with("error: unexpected ')' at byte 2\n  ñ)\n   ^\n"));
+
+        let mut cases = Vec::new();
+        cases.push(("[à-ÿ]+", "naïve", true));
+        cases.push(("[à-ÿ]+", "naive", false));
+        cases.push(("é$", "café\nthé", true));
+        cases.push(("^Ü", "über", false));
+        run_cases(cases);
+    }
+
+    #[test]
+    pub fn test_properties() {
+        let re = Regex::new("\\p{Greek}+").unwrap();
+        let found: Vec<&str> = re.find_iter("abc ΑΒΓ δέλτα 日本").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["ΑΒΓ", "δέλτα"]);
+
+        // categories by short or long name, one-letter groups and \pL
+        let re = Regex::new("\\p{Lu}\\p{lowercase_letter}+\\pN").unwrap();
+        assert_eq!(re.find("x Étéb2").map(|m| m.as_str()), Some("Étéb2"));
+        let re = Regex::new("[\\p{Han}\\p{Hiragana}]+").unwrap();
+        assert_eq!(re.find("Tokyo 東京です!").map(|m| m.as_str()), Some("東京です"));
+
+        // \P{...} is the complement, and never matches the newline
+        let re = Regex::new("\\P{L}+").unwrap();
+        let found: Vec<&str> = re.find_iter("ab12\n€é").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["12", "€"]);
+        assert!(!Regex::new("\\p{Cc}").unwrap().is_match("a\nb"));
+
+        let err = compile("a\\p{Klingon}", &NfaConfig::default()).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::UnknownProperty { name: "Klingon".to_string() });
+        assert_eq!(err.offset, 4);
+        assert!(compile("[\\P{Nope}]", &NfaConfig::default()).is_err());
+
+        let mut cases = Vec::new();
+        cases.push(("^\\p{Cyrillic}+$", "Привет", true));
+        cases.push(("^\\p{Cyrillic}+$", "Privet", false));
+        cases.push(("[^\\p{L}\\s]", "Straße", false));
+        cases.push(("\\p{Sc}\\d", "cost: ¥5", true));
+        run_cases(cases);
+    }
+
+    #[test]
+    pub fn test_case_insensitive() {
+        let ignore_case = NfaConfig { case_insensitive: true, ..NfaConfig::default() };
+        let re = compile("error", &ignore_case).unwrap();
+        let found: Vec<&str> = re.find_iter("Error, ERROR, error, eRRor!").map(|m| m.as_str()).collect();
+        assert_eq!(found, vec!["Error", "ERROR", "error", "eRRor"]);
+
+        // simple case folding goes beyond ASCII: k also matches the Kelvin sign
+        let re = Regex::new("(?i)k[a-c]\\p{Lu}ß").unwrap();
+        assert_eq!(re.find("x \u{212a}BéẞK").map(|m| m.as_str()), Some("\u{212a}Béẞ"));
+        let re = Regex::new("(?i)[^a-z]+").unwrap();
+        assert_eq!(re.find("abcXYZ\u{212a}17").map(|m| m.as_str()), Some("17"));
+
+        // (?i) lasts to the end of its group and (?-i) or (?i:...) scope it
+        let mut cases = Vec::new();
+        cases.push(("(?i)ab(?-i)c", "ABc", true));
+        cases.push(("(?i)ab(?-i)c", "ABC", false));
+        cases.push(("((?i)a)b", "Ab", true));
+        cases.push(("((?i)a)b", "AB", false));
+        cases.push(("a(?i:b)c", "aBc", true));
+        cases.push(("a(?i:b)c", "aBC", false));
+        cases.push(("x(?i)y|z", "Z", true));
+        cases.push(("(?i)a{3}", "aAa", true));
+        cases.push(("(?-i:a)", "A", false));
+        run_cases(cases);
+
+        // the literal prefix is searched for without regard to case, on every
+        // engine
+        let lazy = NfaConfig { dfa_state_limit: 1, ..ignore_case.clone() };
+        let nfa_only = NfaConfig { dfa_state_limit: 1, dfa_cache_limit: 0, ..ignore_case.clone() };
+        for pattern in ["warn\\d", "(?-i:W)arn", "WA(?-i)rn", "(?i:W)ARN", "w(a|b)"] {
+            let spans = |config: &NfaConfig| -> Vec<(usize, usize)> {
+                compile(pattern, config).unwrap().find_iter("WARN1 warn2 Warn wb Wrn").map(|m| (m.start(), m.end())).collect()
+            };
+            assert_eq!(spans(&ignore_case), spans(&lazy), "{}", pattern);
+            assert_eq!(spans(&ignore_case), spans(&nfa_only), "{}", pattern);
+        }
+        let spans = |pattern: &str| -> Vec<(usize, usize)> {
+            compile(pattern, &ignore_case).unwrap().find_iter("WARN1 warn2 Warn").map(|m| (m.start(), m.end())).collect()
+        };
+        assert_eq!(spans("warn\\d"), vec![(0, 5), (6, 11)]);
+        assert_eq!(spans("(?-i:W)arn"), vec![(0, 4), (12, 16)]);
+        assert_eq!(spans("WA(?-i)rn"), vec![(6, 10), (12, 16)]);
+
+        assert_eq!(string_search_ignore_case("Hello HELLO hello", &"hello".to_string()), vec![5, 11, 17]);
+        assert_eq!(string_search_ignore_case("straSSe STRASSE", &"strasse".to_string()), vec![7, 15]);
+    }
+
+    #[test]
+    pub fn test_cli() {
+        let args = |line: &str| -> Vec<String> { line.split(' ').map(String::from).collect() };
+        let options = match parse_args(&args("-inv -e foo --regexp=bar -fpats.txt -- -x a.txt")) {
+            Ok(Command::Search(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert!(options.ignore_case && options.line_number && options.invert && !options.whole_line);
+        assert_eq!(options.patterns, vec!["foo", "bar"]);
+        assert_eq!(options.pattern_files, vec!["pats.txt"]);
+        assert_eq!(options.files, vec!["-x", "a.txt"]);
+
+        // without -e the first operand is the pattern
+        let options = match parse_args(&args("-c --count ab+ a.txt b.txt")) {
+            Ok(Command::Search(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert!(options.count);
+        assert_eq!(options.patterns, vec!["ab+"]);
+        assert_eq!(options.files, vec!["a.txt", "b.txt"]);
+
+        assert_eq!(parse_args(&args("-n --help")), Ok(Command::Help));
+        assert_eq!(parse_args(&args("--version x")), Ok(Command::Version));
+        match parse_args(&args("--silent -q ab")) {
+            Ok(Command::Search(options)) => assert!(options.quiet),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!((exit_status(true, false), exit_status(false, false)), (0, 1));
+        assert_eq!((exit_status(true, true), exit_status(false, true)), (2, 2));
+        // no file reads standard input
+        match parse_args(&args("ab")) {
+            Ok(Command::Search(options)) => assert!(options.files.is_empty()),
+            other => panic!("{:?}", other),
+        }
+        for (line, message) in [
+            ("-n", "no pattern given"),
+            ("-j ab a.txt", "invalid option -- 'j'"),
+            ("ab a.txt -e", "option requires an argument -- 'e'"),
+            ("--colr ab a.txt", "unrecognized option '--colr'"),
+            ("--color=sometimes ab", "invalid argument 'sometimes' for '--color'"),
+            ("--count=2 ab a.txt", "option '--count' doesn't allow an argument"),
+        ] {
+            assert_eq!(parse_args(&args(line)).err().map(|e| e.message), Some(message.to_string()), "{}", line);
+        }
+    }
+
+    #[test]
+    pub fn test_search_text() {
+        let text = "an error\nERROR: disk\nterror\nall good\nerror\n";
+        assert_eq!(grep_lines(&["error"], text), vec!["an error", "terror", "error"]);
+        assert_eq!(grep_lines(&["-n", "error"], text), vec!["1:an error", "3:terror", "5:error"]);
+        assert_eq!(grep_lines(&["-i", "-o", "e?rror"], text), vec!["error", "ERROR", "error", "error"]);
+        assert_eq!(grep_lines(&["-v", "error"], text), vec!["ERROR: disk", "all good"]);
+        assert_eq!(grep_lines(&["-w", "-n", "error"], text), vec!["1:an error", "5:error"]);
+        assert_eq!(grep_lines(&["-x", "error"], text), vec!["error"]);
+        assert_eq!(grep_lines(&["-x", "-i", "e.+"], text), vec!["ERROR: disk", "error"]);
+        assert_eq!(grep_lines(&["-c", "error"], text), vec!["3"]);
+        assert_eq!(grep_lines(&["-c", "-v", "error"], text), vec!["2"]);
+        assert_eq!(grep_lines(&["-l", "good"], text), vec!["log"]);
+        assert_eq!(grep_lines(&["-L", "good"], text), Vec::<String>::new());
+        assert_eq!(grep_lines(&["-L", "bad"], text), vec!["log"]);
+        assert_eq!(grep_lines(&["-e", "disk", "-e", "good"], text), vec!["ERROR: disk", "all good"]);
+        // -w moves on to a later match when the first is inside a word
+        assert_eq!(grep_lines(&["-w", "-o", ".error"], text), vec!["terror"]);
+        // and before that tries shorter matches from the same start
+        let regex = Regex::new("[a-z ]+").unwrap();
+        let word = Options { word: true, ..Options::default() };
+        let found = Searcher::new(Some(&regex), &word).search("foo bar1\nbaz1\n");
+        assert_eq!(found, vec![LineMatch { number: 1, start: 0, line: b"foo bar1", spans: vec![(0, 3)], captures: vec![], context: false }]);
+        assert_eq!(regex.match_ends("foo bar1", 0), vec![1, 2, 3, 4, 5, 6, 7]);
+        // the NFA finds the same ends when the lazy DFA gives up
+        let nfa_only = NfaConfig { dfa_state_limit: 0, dfa_cache_limit: 0, ..NfaConfig::default() };
+        let text = "foo bar1 ".repeat(50);
+        for pattern in ["[a-z ]*a[a-z ][a-z ][a-z ]", "oo ba"] {
+            let regex = compile(pattern, &nfa_only).unwrap();
+            assert_eq!(regex.match_ends(&text, 1), Regex::new(pattern).unwrap().match_ends(&text, 1), "{}", pattern);
+        }
+
+        // the records behind the output: line numbers, line offsets and spans
+        let regex = Regex::new("o+").unwrap();
+        let found = Searcher::new(Some(&regex), &Options::default()).search("foo\nbar\nboo zoo\n");
+        assert_eq!(found, vec![
+            LineMatch { number: 1, start: 0, line: b"foo", spans: vec![(1, 3)], captures: vec![], context: false },
+            LineMatch { number: 3, start: 8, line: b"boo zoo", spans: vec![(1, 3), (5, 7)], captures: vec![], context: false },
+        ]);
+        let invert = Options { invert: true, ..Options::default() };
+        let found = Searcher::new(Some(&regex), &invert).search("foo\nbar\n");
+        assert_eq!(found, vec![LineMatch { number: 2, start: 4, line: b"bar", spans: vec![], captures: vec![], context: false }]);
+        assert!(Searcher::new(None, &Options::default()).search("foo\n").is_empty());
+
+        // a stream is searched in chunks but finds what the whole text does,
+        // \A and \z included
+        let text: String = (1..30000).map(|i| format!("line {}\n", i)).collect::<String>() + "last";
+        for (pattern, flags) in [("\\Aline 1$", ""), ("^line 1\\d*7$", "-x"), ("st\\z", ""), ("\\z", "-v"), ("2", "-w")] {
+            let options = match flags {
+                "" => Options::default(),
+                "-x" => Options { whole_line: true, ..Options::default() },
+                "-w" => Options { word: true, ..Options::default() },
+                _ => Options { invert: true, ..Options::default() },
+            };
+            let regex = Regex::new(pattern).unwrap();
+            let searcher = Searcher::new(Some(&regex), &options);
+            let mut streamed: Vec<String> = Vec::new();
+            // A small buffer makes many chunks
+            let count = searcher.search_reader(BufReader::with_capacity(4096, text.as_bytes()), |m| {
+                streamed.push(format!("{:?}", m));
+                return true;
+            }).unwrap();
+            let whole: Vec<String> = searcher.search(&text).iter().map(|m| format!("{:?}", m)).collect();
+            assert_eq!((count, &streamed), (whole.len(), &whole), "{}", pattern);
+        }
+        // stopping early, and input that is not UTF-8, whose lines are
+        // handed over as they were read with offsets into them
+        let searcher = Searcher::new(Some(&regex), &Options::default());
+        assert_eq!(searcher.search_reader("foo\nboo\n".as_bytes(), |_| false).unwrap(), 1);
+        let mut lines = Vec::new();
+        searcher.search_reader(&b"caf\xe9\nfoo\n"[..], |m| { lines.push((m.number, m.start)); return true; }).unwrap();
+        assert_eq!(lines, vec![(2, 5)]);
+        let latin1 = Regex::new("f.").unwrap();
+        let context = Options { before_context: Some(1), ..Options::default() };
+        let mut lines = Vec::new();
+        Searcher::new(Some(&latin1), &context).search_reader(&b"\xff\xfe\ncaf\xe9 \xe0 la f\xeate\n"[..], |m| {
+            lines.push((m.number, m.start, m.line.to_vec(), m.spans.clone()));
+            return true;
+        }).unwrap();
+        assert_eq!(lines, vec![
+            (1, 0, b"\xff\xfe".to_vec(), vec![]),
+            (2, 3, b"caf\xe9 \xe0 la f\xeate".to_vec(), vec![(2, 4), (10, 12)]),
+        ]);
+        // and are printed unchanged, -o and --json included
+        let latin1_text = &b"caf\xe9\nthe caf\xe9 is open\n"[..];
+        assert_eq!(grep(&["caf"], latin1_text), latin1_text);
+        assert_eq!(grep(&["-o", "f. is"], latin1_text), b"f\xe9 is\n");
+        let out = String::from_utf8(grep(&["--json", "(i)s"], latin1_text)).unwrap();
+        let event = Event::from_json(&Json::parse(out.lines().nth(1).unwrap()).unwrap()).unwrap();
+        let Event::Match { offset, line, submatches, .. } = event else { panic!("{:?}", event) };
+        assert_eq!((offset, line.as_str()), (5, "the caf\u{fffd} is open"));
+        assert_eq!((submatches[0].start, submatches[0].end, submatches[0].captures[0].as_ref().map(|c| c.start)), (9, 11, Some(9)));
+
+        // several patterns, an error names the one at fault
+        let patterns = vec!["a+".to_string(), "b[".to_string()];
+        let (pattern, e) = compile_patterns(&patterns, &NfaConfig::default()).err().unwrap();
+        assert_eq!((pattern.as_str(), e.offset), ("b[", 2));
+    }
+
+    #[test]
+    pub fn test_empty_pattern() {
+        let text = "foo\n\nbar\n";
+        // an empty pattern selects every line, alone or next to others
+        assert_eq!(grep_lines(&[""], text), vec!["foo", "", "bar"]);
+        assert_eq!(grep_lines(&["-e", ""], text), vec!["foo", "", "bar"]);
+        assert_eq!(grep_lines(&["-e", "", "-e", "o+"], text), vec!["foo", "", "bar"]);
+        assert_eq!(grep_lines(&["-o", "-e", "", "-e", "o+"], text), vec!["oo"]);
+        assert_eq!(grep_lines(&["-c", "-e", ""], text), vec!["3"]);
+        assert_eq!(grep_lines(&["-v", "-e", ""], text), Vec::<String>::new());
+        assert_eq!(grep_lines(&["-x", "-n", "-e", ""], text), vec!["2:"]);
+        assert!(Regex::new("").unwrap().find("foo").is_none());
+
+        // so does any pattern whose only match on a line is empty
+        assert_eq!(grep_lines(&["-n", "^$"], text), vec!["2:"]);
+        assert_eq!(grep_lines(&["-v", "^$"], text), vec!["foo", "bar"]);
+        assert_eq!(grep_lines(&["-c", "^"], text), vec!["3"]);
+        assert_eq!(grep_lines(&["-c", "x*"], text), vec!["3"]);
+        assert_eq!(grep_lines(&["-x", "-n", "b*"], text), vec!["2:"]);
+        assert_eq!(grep_lines(&["-x", "-n", "a*"], text), vec!["2:"]);
+        assert_eq!(grep_lines(&["-w", "-n", "x*"], text), vec!["2:"]);
+        assert_eq!(grep_lines(&["-o", "-e", "x*", "-e", "o"], text), vec!["o", "o"]);
+        assert_eq!(grep_lines(&["-c", "-e", "^$", "-e", "bar"], text), vec!["2"]);
+        let regex = Regex::new("$").unwrap();
+        assert!(regex.can_match_empty() && regex.find("foo").is_none());
+        assert_eq!((regex.matches_empty_at("ab", 1), regex.matches_empty_at("ab", 2)), (false, true));
+        assert!(!Regex::new("a+").unwrap().can_match_empty());
+
+        // as does a blank line in a pattern file
+        let file = std::env::temp_dir().join(format!("grep_patterns_{}", std::process::id()));
+        fs::write(&file, "xyz\n\n").unwrap();
+        let filename = file.display().to_string();
+        assert_eq!(grep_lines(&["-f", &filename], text), vec!["foo", "", "bar"]);
+        assert_eq!(grep_lines(&["-o", "-f", &filename], text), Vec::<String>::new());
+        fs::write(&file, "xyz\nb.r\n").unwrap();
+        assert_eq!(grep_lines(&["-f", &filename], text), vec!["bar"]);
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    pub fn test_broken_pipe() {
+        // a reader that went away fails every write
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let selected = |args: &[&str]| -> bool {
+            let options = search_options(args);
+            let mut printer = Printer::new(Closed, &options, false);
+            match grep_with(&options, &mut printer, b"foo\nbar\n") {
+                Err(Failure::Write(e, found)) if e.kind() == io::ErrorKind::BrokenPipe => found,
+                other => panic!("{:?}", other),
+            }
+        };
+        assert!(selected(&["foo"]));
+        assert!(!selected(&["-c", "nomatch"]));
+        assert!(!selected(&["-L", "nomatch"]));
+        assert!(selected(&["--json", "bar"]));
+        assert_eq!((exit_status(false, false), exit_status(true, false)), (1, 0));
+    }
+
+    #[test]
+    pub fn test_streaming() {
+        // Input arriving a piece at a time, which notes what had been
+        // written by the time each piece is read
+        struct Pipe {
+            pieces: VecDeque<&'static [u8]>,
+            written: Rc<RefCell<Vec<u8>>>,
+            seen: Vec<String>,
+        }
+        impl io::Read for Pipe {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.seen.push(String::from_utf8(self.written.borrow().clone()).unwrap());
+                let Some(piece) = self.pieces.pop_front() else { return Ok(0) };
+                buf[..piece.len()].copy_from_slice(piece);
+                return Ok(piece.len());
+            }
+        }
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                return Ok(buf.len());
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let seen = |args: &[&str], pieces: &[&'static [u8]]| -> Vec<String> {
+            let options = search_options(args);
+            let written = Rc::new(RefCell::new(Vec::new()));
+            let mut printer = Printer::new(BufWriter::new(Shared(Rc::clone(&written))), &options, false);
+            let mut pipe = Pipe { pieces: pieces.iter().copied().collect(), written, seen: Vec::new() };
+            let regex = compile_patterns(&read_patterns(&options).unwrap(), &nfa_config(&options)).unwrap();
+            let searcher = Searcher::new(Some(&regex), &options);
+            print_results(&mut printer, &options, &searcher, "log", BufReader::new(&mut pipe)).unwrap();
+            return pipe.seen;
+        };
+        // a line is printed before the next one is waited for, even one
+        // that arrived in pieces or with the start of the next
+        assert_eq!(seen(&["foo"], &[b"foo\n", b"bar\n"]), vec!["", "foo\n", "foo\n"]);
+        assert_eq!(seen(&["foo"], &[b"fo", b"o\nb", b"ar foo\n"]), vec!["", "", "foo\n", "foo\nbar foo\n"]);
+        assert_eq!(seen(&["--json", "foo"], &[b"foo\n", b"bar\n"])[1].lines().count(), 2);
+        // context lines after a match are printed as they come
+        assert_eq!(seen(&["-A1", "foo"], &[b"foo\n", b"bar\n", b"baz\n"]), vec!["", "foo\n", "foo\nbar\n", "foo\nbar\n"]);
+    }
+
+    #[test]
+    pub fn test_walk() {
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(!glob_match("*.rs", "main.rsx"));
+        assert!(glob_match("?.t[x-z]t", "a.txt"));
+        assert!(!glob_match("[!a]*", "abc"));
+        assert!(glob_match("\\*", "*"));
+        assert!(!glob_match("\\*", "a"));
+
+        let args = |line: &str| line.split_whitespace().map(String::from).collect::<Vec<String>>();
+        let Ok(Command::Search(options)) = parse_args(&args("-rh --include=*.rs --exclude *.txt foo")) else { panic!() };
+        assert!(options.recursive && !options.follow_symlinks && options.files.is_empty());
+        assert_eq!(options.with_filename, Some(false));
+        assert_eq!((options.include, options.exclude), (vec!["*.rs".to_string()], vec!["*.txt".to_string()]));
+        let Ok(Command::Search(options)) = parse_args(&args("--dereference-recursive foo")) else { panic!() };
+        assert!(options.recursive && options.follow_symlinks);
+
+        let root = std::env::temp_dir().join(format!("grep_walk_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        for file in ["a/one.txt", "a/b/two.rs", "three.rs"] {
+            fs::write(root.join(file), "x\n").unwrap();
+        }
+        let walk_with = |recursive: bool, follow_symlinks: bool, include: &[&str], exclude: &[&str]| {
+            let include: Vec<String> = include.iter().map(|g| g.to_string()).collect();
+            let exclude: Vec<String> = exclude.iter().map(|g| g.to_string()).collect();
+            let paths = vec![root.display().to_string()];
+            Walker::new(&paths, recursive, follow_symlinks, &include, &exclude).map(|entry| match entry {
+                Entry::File(path) => path.strip_prefix(&root).unwrap().display().to_string(),
+                Entry::Loop(path) => format!("loop {}", path.strip_prefix(&root).unwrap().display()),
+                Entry::Error(_, e) => format!("error {}", e.kind()),
+            }).collect::<Vec<String>>()
+        };
+        let walk = |recursive: bool, include: &[&str], exclude: &[&str]| walk_with(recursive, false, include, exclude);
+        assert_eq!(walk(true, &[], &[]), vec!["a/b/two.rs", "a/one.txt", "three.rs"]);
+        assert_eq!(walk(true, &["*.rs"], &[]), vec!["a/b/two.rs", "three.rs"]);
+        assert_eq!(walk(true, &[], &["t*"]), vec!["a/one.txt"]);
+        assert_eq!(walk(false, &[], &[]).len(), 1);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("..", root.join("a/b/up")).unwrap();
+            std::os::unix::fs::symlink("../three.rs", root.join("a/link.rs")).unwrap();
+            // -r skips symlinks met inside directories, -R follows them
+            assert_eq!(walk(true, &[], &[]), vec!["a/b/two.rs", "a/one.txt", "three.rs"]);
+            assert_eq!(walk_with(true, true, &[], &[]), vec!["a/b/two.rs", "loop a/b/up", "a/link.rs", "a/one.txt", "three.rs"]);
+            // but both follow the ones named on the command line
+            let paths = vec![root.join("a/link.rs").display().to_string()];
+            assert_eq!(Walker::new(&paths, true, false, &[], &[]).count(), 1);
+        }
+
+        // errors opening a file keep their cause
+        assert!(open_input(&root.join("three.rs")).is_ok());
+        assert_eq!(open_input(&root.join("missing.txt")).err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    pub fn test_context() {
+        let text = "1 a\n2 b\n3 ERR\n4 c\n5 d\n6 e\n7 ERR\n8 f\n9 ERR\n10 g\n11 h\n";
+        assert_eq!(grep_text(&["-n", "-C1", "ERR"], text), "2-2 b\n3:3 ERR\n4-4 c\n--\n6-6 e\n7:7 ERR\n8-8 f\n9:9 ERR\n10-10 g\n");
+        assert_eq!(grep_text(&["-A1", "ERR"], text), "3 ERR\n4 c\n--\n7 ERR\n8 f\n9 ERR\n10 g\n");
+        assert_eq!(grep_text(&["-B", "3", "-A0", "ERR"], text), "1 a\n2 b\n3 ERR\n4 c\n5 d\n6 e\n7 ERR\n8 f\n9 ERR\n");
+        // -C gives way to -A and -B, and -C0 still separates groups
+        assert_eq!(grep_text(&["-A0", "--context=1", "ERR"], text), "2 b\n3 ERR\n--\n6 e\n7 ERR\n8 f\n9 ERR\n");
+        assert_eq!(grep_text(&["-C0", "ERR"], text), "3 ERR\n--\n7 ERR\n--\n9 ERR\n");
+        // context is left out of counts
+        assert_eq!(grep_text(&["-c", "-C2", "ERR"], text), "3\n");
+        assert_eq!(grep_text(&["-vn", "-B1", "[a-g]"], text), "2-2 b\n3:3 ERR\n--\n6-6 e\n7:7 ERR\n8-8 f\n9:9 ERR\n10-10 g\n11:11 h\n");
+        assert_eq!(parse_args(&["-Cx".to_string(), "a".to_string()]).err().map(|e| e.message), Some("x: invalid context length argument".to_string()));
+
+        // the lines kept for -B and owed to -A carry over between chunks
+        let lines: Vec<String> = (1..40000).map(|i| format!("line {}", i)).collect();
+        let text = lines.join("\n") + "\n";
+        let selected = |i: usize| i >= 1000 && [0, 3, 500, 503].contains(&(i % 1000));
+        let (before, after) = (3, 2);
+        let mut expected = String::new();
+        let mut last = 0;
+        for i in 1..40000usize {
+            // printed if a selected line is at most `after` lines before or
+            // `before` lines after
+            if !(i.saturating_sub(after).max(1)..=(i + before).min(39999)).any(selected) {
+                continue;
+            }
+            if last != 0 && i > last + 1 {
+                expected.push_str("--\n");
+            }
+            let marker = if selected(i) { ':' } else { '-' };
+            expected.push_str(&format!("{}{}line {}\n", i, marker, i));
+            last = i;
+        }
+        assert_eq!(grep_text(&["-n", "-B3", "-A2", "^line \\d+(000|003|500|503)$"], &text), expected);
+    }
+
+    #[test]
+    pub fn test_color() {
+        let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<String>>();
+        for (line, color) in [("a", ColorChoice::Never), ("--color a", ColorChoice::Auto), ("--colour=always a", ColorChoice::Always), ("--color=always --color=never a", ColorChoice::Never)] {
+            let Ok(Command::Search(options)) = parse_args(&args(line)) else { panic!("{}", line) };
+            assert_eq!(options.color, color, "{}", line);
+        }
+
+        let colors = Colors::parse("mt=04:fn=:sl=1:ne:xx=3:ln=bad");
+        assert_eq!((colors.selected_match.as_str(), colors.context_match.as_str()), ("04", "04"));
+        assert_eq!((colors.filename.as_str(), colors.selected_line.as_str(), colors.line_number.as_str()), ("", "1", "32"));
+        assert!(!colors.erase);
+        assert_eq!(Colors::parse(""), Colors::default());
+
+        let print = |flag: &str, colors: Colors| -> String {
+            let options = search_options(&[flag, "o+"]);
+            let mut printer = Printer::new(Vec::new(), &options, true).with_colors(colors);
+            grep_with(&options, &mut printer, b"foo boo\nbar\nx\nzoo\n").unwrap();
+            String::from_utf8(printer.into_inner()).unwrap()
+        };
+        let plain = Colors { erase: false, ..Colors::default() };
+        assert_eq!(print("-n", plain.clone()),
+            "\x1b[35mlog\x1b[m\x1b[36m:\x1b[m\x1b[32m1\x1b[m\x1b[36m:\x1b[mf\x1b[01;31moo\x1b[m b\x1b[01;31moo\x1b[m\n\
+             \x1b[35mlog\x1b[m\x1b[36m:\x1b[m\x1b[32m4\x1b[m\x1b[36m:\x1b[mz\x1b[01;31moo\x1b[m\n");
+        assert_eq!(print("-A1", plain.clone()),
+            "\x1b[35mlog\x1b[m\x1b[36m:\x1b[mf\x1b[01;31moo\x1b[m b\x1b[01;31moo\x1b[m\n\
+             \x1b[35mlog\x1b[m\x1b[36m-\x1b[mbar\n\x1b[36m--\x1b[m\n\
+             \x1b[35mlog\x1b[m\x1b[36m:\x1b[mz\x1b[01;31moo\x1b[m\n");
+        assert_eq!(print("-c", plain.clone()), "\x1b[35mlog\x1b[m\x1b[36m:\x1b[m2\n");
+        assert_eq!(print("-o", Colors::parse("fn=:se=:ms=7")), "log:\x1b[7m\x1b[Koo\x1b[m\x1b[K\nlog:\x1b[7m\x1b[Koo\x1b[m\x1b[K\nlog:\x1b[7m\x1b[Koo\x1b[m\x1b[K\n");
+        assert_eq!(print("-n", Colors::none()), "log:1:foo boo\nlog:4:zoo\n");
+    }
+
+    #[test]
+    pub fn test_json() {
+        // values survive being written and read back
+        let value = Json::Object(vec![
+            ("s".to_string(), Json::String("q\"\\\n\t\u{1}é😀".to_string())),
+            ("a".to_string(), Json::Array(vec![Json::Null, Json::Bool(true), Json::Number(42.0), Json::Array(vec![])])),
+        ]);
+        assert_eq!(value.to_string(), "{\"s\":\"q\\\"\\\\\\n\\t\\u0001é😀\",\"a\":[null,true,42,[]]}");
+        assert_eq!(Json::parse(&value.to_string()), Ok(value));
+        assert_eq!(Json::parse(" [1.5e1, -2, \"\\ud83d\\ude00\\/\"] "), Ok(Json::Array(vec![Json::Number(15.0), Json::Number(-2.0), Json::String("😀/".to_string())])));
+        assert_eq!(Json::parse("{\"a\" 1}"), Err("expected ':' at byte 5".to_string()));
+        assert_eq!(Json::parse("[1,]"), Err("expected a value at byte 3".to_string()));
+        assert_eq!(Json::parse("\"\\x\""), Err("expected an escape at byte 2".to_string()));
+
+        // the events printed are the searcher's results, and read back as them
+        let text = "date 2024-01-05\nnothing\nx \"q\"\t2023-12-31\n";
+        let pattern = "(?P<y>\\d{4})-(\\d\\d)(-(?<d>\\d\\d))?|q";
+        let out = grep_text(&["--json", "-C1", pattern], text);
+        let events: Vec<Event> = out.lines().map(|l| Event::from_json(&Json::parse(l).unwrap()).unwrap()).collect();
+        let regex = Regex::new(pattern).unwrap();
+        let searcher = Searcher::new(Some(&regex), &search_options(&["--json", "-C1", pattern]));
+        let mut expected = vec![Event::Begin { path: "log".to_string() }];
+        searcher.search_reader(text.as_bytes(), |line| {
+            expected.push(Event::from_line("log", line, Some(&regex)));
+            return true;
+        }).unwrap();
+        expected.push(Event::End { path: "log".to_string(), stats: FileStats { matched_lines: 2, matches: 3 } });
+        assert_eq!(events, expected);
+        for (line, event) in out.lines().zip(events.iter()) {
+            assert_eq!(event.to_json().to_string(), line);
+        }
+
+        let Event::Match { line_number, offset, submatches, .. } = &events[3] else { panic!("{:?}", events[3]) };
+        assert_eq!((*line_number, *offset), (3, 24));
+        assert_eq!((submatches[0].text.as_str(), submatches[0].captures.clone()), ("q", vec![None, None, None, None]));
+        let year = Capture { name: Some("y".to_string()), start: 6, end: 10, text: "2023".to_string() };
+        assert_eq!(submatches[1].captures[0], Some(year));
+        assert_eq!(submatches[1].captures[1].as_ref().map(|c| c.name.clone()), Some(None));
+        assert!(matches!(&events[2], Event::Context { line_number: 2, offset: 16, .. }));
+        // groups come from the match the search found, even where running
+        // the pattern on the printed line alone finds another or none
+        let groups = |args: &[&str], text: &str| -> Vec<Option<(usize, usize, String)>> {
+            let out = grep_text(args, text);
+            let event = Event::from_json(&Json::parse(out.lines().nth(1).unwrap()).unwrap()).unwrap();
+            let Event::Match { submatches, .. } = event else { panic!("{:?}", event) };
+            return submatches[0].captures.iter().map(|c| c.as_ref().map(|c| (c.start, c.end, c.text.clone()))).collect();
+        };
+        assert_eq!(groups(&["--json", "-U", "(a)\\n"], "a\nb\n"), vec![Some((0, 1, "a".to_string()))]);
+        assert_eq!(groups(&["--json", "-U", "b\\n(c)"], "ab\ncd\n"), vec![Some((3, 4, "c".to_string()))]);
+        assert_eq!(groups(&["--json", "-w", "([a-z]+)( [a-z]+)?"], "foo bar1\n"), vec![Some((0, 3, "foo".to_string())), None]);
+
+        // nothing for an input without lines, and no mixing with -c
+        assert!(grep(&["--json", "-C1", pattern], b"none\n").is_empty());
+        let args: Vec<String> = ["--json", "-c", "a"].iter().map(|a| a.to_string()).collect();
+        assert_eq!(parse_args(&args).err().map(|e| e.message), Some("--json cannot be used with -c, -l, -L or -q".to_string()));
+    }
+
+    #[test]
+    pub fn test_multiline() {
+        // a newline is only matchable in multiline mode
+        let err = Regex::new("a\\nb").err().unwrap();
+        assert_eq!((err.kind.clone(), err.offset), (ErrorKind::NewlineNotAllowed, 1));
+        assert!(Regex::new("[\\n]").is_err());
+        let multiline = NfaConfig { multiline: true, ..NfaConfig::default() };
+        let lazy = NfaConfig { dfa_state_limit: 1, ..multiline.clone() };
+        let nfa_only = NfaConfig { dfa_state_limit: 1, dfa_cache_limit: 0, ..multiline.clone() };
+        let mut cases: Vec<(&str, &str, Option<&str>)> = Vec::new();
+        cases.push(("a\\nb", "xa\nby", Some("a\nb")));
+        cases.push(("[^x]+", "ab\ncx", Some("ab\nc")));
+        cases.push(("\\D\\D", "a\nb", Some("a\n")));
+        // '.' only takes the newline along with (?s)
+        cases.push(("a.b", "a\nb", None));
+        cases.push(("(?s)a.b", "a\nb", Some("a\nb")));
+        cases.push(("(?-m)a[^x]", "a\nb", None));
+        // ^ holds after a newline inside a match, $ before one
+        cases.push(("1$\\n^2", "1\n2", Some("1\n2")));
+        cases.push(("\\d\\n^\\d+$", "a1\n23\n45b", Some("1\n23")));
+        for config in [multiline.clone(), lazy, nfa_only] {
+            for (pattern, text, expected) in cases.iter() {
+                let regex = compile(pattern, &config).unwrap();
+                assert_eq!(regex.find(text).map(|m| m.as_str()), *expected, "{} in {:?}", pattern, text);
+            }
+        }
+        // the inline flags turn multiline mode on without -U
+        assert_eq!(Regex::new("(?m)b\\nc").unwrap().find("ab\ncd").map(|m| m.start()), Some(1));
+        assert_eq!(Regex::new("(?s:.)").unwrap().find("\n").map(|m| m.end()), Some(1));
+        assert!(Regex::new("(?im)A\\n").unwrap().multiline());
+        assert!(!Regex::new("(?m)a").unwrap().multiline());
+
+        // a match is printed from its first line through its last
+        let text = "fn a() {\n}\nfn b() {}\nlet c = {\n  1\n};\n";
+        assert_eq!(grep_text(&["-Un", "\\{\\n[^}]*\\}"], text), "1:fn a() {\n}\n4:let c = {\n  1\n};\n");
+        assert_eq!(grep_text(&["-U", "-o", "\\{\\n[^}]*\\}"], text), "{\n}\n{\n  1\n}\n");
+        assert_eq!(grep_text(&["-Uc", "\\{\\n[^}]*\\}"], text), "2\n");
+        assert_eq!(grep_text(&["-Uv", "\\{\\n[^}]*\\}"], text), "fn b() {}\n");
+        assert_eq!(grep_text(&["-Un", "-A1", "b\\(\\) \\{\\}\\n"], text), "3:fn b() {}\n4-let c = {\n");
+        assert_eq!(grep_text(&["-Un", "-B1", "c = \\{\\n"], text), "3-fn b() {}\n4:let c = {\n");
+        assert_eq!(grep_text(&["-Un", "-C1", "^  1\\n\\};"], text), "4-let c = {\n5:  1\n};\n");
+        assert_eq!(grep_text(&["--multiline", "-x", "fn a\\(\\) \\{\\n\\}"], text), "fn a() {\n}\n");
+        assert_eq!(grep_text(&["-U", "-x", "fn a\\(\\) \\{\\n"], text), "");
+        let err = compile("a\\n", &NfaConfig::default()).err().unwrap();
+        assert_eq!(err.diagnostic("a\\n"), "error: a newline can only be matched in multiline mode, use -U or (?m)\n  a\\n\n   ^");
+
+        // a match still going at the end of a chunk has its lines searched
+        // again with the next, so reading a little at a time selects what
+        // searching the whole input at once does
+        let lines: Vec<String> = (1..300).map(|i| format!("line {}", i)).collect();
+        let text = lines.join("\n") + "\nend";
+        let patterns = ["line 65\\nline 66$", "(?i)LINE 65\\nline 66$", "(?s)line 12\\n.*line 13", "9\\n^line \\d+0$", "\\Aline 1\\n", "[^x]+\\z", "\\n\\n"];
+        for (pattern, flags) in patterns.iter().flat_map(|p| ["", "-v", "-A1", "-B2", "-C1 -v"].map(|f| (*p, f))) {
+            let mut args: Vec<&str> = flags.split_whitespace().collect();
+            args.extend(["-U", pattern]);
+            let options = search_options(&args);
+            let regex = compile(pattern, &nfa_config(&options)).unwrap();
+            let searcher = Searcher::new(Some(&regex), &options);
+            let mut streamed: Vec<String> = Vec::new();
+            let count = searcher.search_reader(BufReader::with_capacity(64, text.as_bytes()), |m| {
+                streamed.push(format!("{:?}", m));
+                return true;
+            }).unwrap();
+            let mut whole: Vec<String> = Vec::new();
+            let whole_count = searcher.search_reader(BufReader::with_capacity(text.len(), text.as_bytes()), |m| {
+                whole.push(format!("{:?}", m));
+                return true;
+            }).unwrap();
+            assert_eq!((count, &streamed), (whole_count, &whole), "{} {}", flags, pattern);
+        }
+        assert_eq!(grep_text(&["-Un", "line 65\\nline 66$"], &text), "65:line 65\nline 66\n");
+        // but only so far
+        let regex = compile("\\A\\n+x", &multiline).unwrap();
+        let endless = io::Read::take(io::repeat(b'\n'), 1 << 20);
+        let searcher = Searcher::new(Some(&regex), &Options::default()).with_multiline_limit(100 * 1024);
+        let err = searcher.search_reader(BufReader::new(endless), |_| true).err().unwrap();
+        assert_eq!(err.to_string(), "a match could run over more than 102400 bytes of lines");
+        let searcher = Searcher::new(Some(&regex), &Options::default()).with_multiline_limit(1 << 20);
+        assert_eq!(searcher.search_reader(&b"\n\n\n"[..], |_| true).unwrap(), 0);
+    }
+
+    #[test]
+    pub fn test_escapes() {
+        let mut cases: Vec<(&str, &str, bool)> = Vec::new();
+        cases.push(("a\\tb", "a\tb", true));
+        cases.push(("\\r\\f\\v\\a\\e", "\r\x0c\x0b\x07\x1b", true));
+        cases.push(("\\x41\\x{42}\\u0043\\u{1F600}", "ABC😀", true));
+        cases.push(("(?i)\\x61", "A", true));
+        cases.push(("[\\x00-\\x1f]", "a\x01", true));
+        cases.push(("[\\t ]+x", "\t x", true));
+        cases.push(("[^\\t]", "\t", false));
+        // any ASCII punctuation or space can be escaped into a literal
+        cases.push(("\\-\\<\\ \\]", "-< ]", true));
+        cases.push(("[\\.\\-]", "-", true));
+        for (pattern, text, expected) in cases {
+            assert_eq!(Regex::new(pattern).unwrap().is_match(text), expected, "{} in {:?}", pattern, text);
+        }
+        assert_eq!(Regex::new("\\u{e9}").unwrap().find("café").map(|m| m.start()), Some(3));
+        assert!(Regex::new("\\x0a").is_err());
+        let multiline = NfaConfig { multiline: true, ..NfaConfig::default() };
+        assert!(compile("a\\x0a", &multiline).unwrap().is_match("a\n"));
+
+        // unknown escapes are errors, not literals
+        let config = NfaConfig::default();
+        for (pattern, escape, offset) in [("a\\q", 'q', 1), ("\\1", '1', 0), ("[a\\A]", 'A', 2), ("x\\é", 'é', 1)] {
+            let err = compile(pattern, &config).err().unwrap();
+            assert_eq!(err, RegexError::new(ErrorKind::UnknownEscape { escape }, offset), "{}", pattern);
+        }
+        assert_eq!(compile("a\\q", &config).err().unwrap().diagnostic("a\\q"), "error: unknown escape sequence '\\q'\n  a\\q\n   ^");
+        let err = compile("\\u{110000}", &config).err().unwrap();
+        assert_eq!(err.to_string(), "invalid escape '\\u{110000}', not a Unicode scalar value at byte 0");
+        assert!(matches!(compile("[\\uD800]", &config).err().unwrap().kind, ErrorKind::InvalidCodePoint { .. }));
+        assert_eq!(compile("\\x4", &config).err().unwrap().to_string(), "syntax error at byte 3, expected a hex digit");
+        assert_eq!(compile("\\x{4", &config).err().unwrap().to_string(), "syntax error at byte 4, expected a hex digit or '}'");
+    }
+
+    #[test]
+    pub fn test_posix_classes() {
+        // --posix-classes brings back \s for letters and \w for tab and space
+        let Ok(Command::Search(options)) = parse_args(&["--posix-classes".to_string(), "a".to_string()]) else { panic!() };
+        assert!(options.posix_classes);
+        let posix = NfaConfig { posix_classes: true, ..NfaConfig::default() };
+        let mut cases: Vec<(&str, &str, bool)> = Vec::new();
+        cases.push(("\\s", "123", false));
+        cases.push(("\\s", "abc", true));
+        cases.push(("\\s", " \r", false));
+        cases.push(("\\w", "abc_1", false));
+        cases.push(("\\w", "\t", true));
+        cases.push(("\\S", "abc", false));
+        cases.push(("\\W", "   ", false));
+        cases.push(("[^\\d\\s]", "12ab", false));
+        cases.push(("(\\s\\d)|(\\s\\s)", "a1", true));
+        for (pattern, text, expected) in cases {
+            assert_eq!(compile(pattern, &posix).unwrap().is_match(text), expected, "{} in {:?}", pattern, text);
+        }
+
+        // \s takes a newline in multiline mode, \S never does
+        let multiline = NfaConfig { multiline: true, ..NfaConfig::default() };
+        assert_eq!(compile("a\\s+b", &multiline).unwrap().find("a \n b").map(|m| m.end()), Some(5));
+        assert!(!compile("a\\S", &multiline).unwrap().is_match("a\n"));
+        assert!(compile("a\\W", &multiline).unwrap().is_match("a\n"));
+        assert!(!Regex::new("a\\s").unwrap().is_match("a\n"));
+    }
 }
@@ -1,6 +1,15 @@
 pub mod earley_parse;
 pub mod grammar;
 pub mod nfa;
+pub mod dfa;
 pub mod state_utils;
 pub mod boyer_moore;
-pub mod regex;
\ No newline at end of file
+pub mod regex;
+pub mod error;
+pub mod cli;
+pub mod search;
+pub mod output;
+pub mod json;
+pub mod walk;
+pub mod unicode;
+pub mod unicode_tables;
\ No newline at end of file
@@ -1,12 +1,13 @@
 use std::{collections::HashMap, cmp::max};
 
-pub fn match_length(pattern: &String, mut idx1: usize, mut idx2: usize) -> usize {
+// The tables below work on the pattern's bytes, like the search itself, so
+// multi-byte characters need no special handling
+pub fn match_length(pattern: &[u8], mut idx1: usize, mut idx2: usize) -> usize {
     if idx1 == idx2 {
         return pattern.len() - idx1;
     }
-    let pattern_i = pattern.as_bytes();
     let mut match_count:usize = 0;
-    while idx1 < pattern.len() && idx2 < pattern.len() && pattern_i[idx1] == pattern_i[idx2] {
+    while idx1 < pattern.len() && idx2 < pattern.len() && pattern[idx1] == pattern[idx2] {
         match_count += 1;
         idx1 += 1;
         idx2 += 1;
@@ -15,8 +16,8 @@ pub fn match_length(pattern: &String, mut idx1: usize, mut idx2: usize) -> usize
 
 }
 
-pub fn preprocess(pattern: String) -> Vec<usize> {
-    if pattern.len() == 0 {
+pub fn preprocess(pattern: &[u8]) -> Vec<usize> {
+    if pattern.is_empty() {
         return Vec::new();
     }
     if pattern.len() == 1 {
@@ -28,7 +29,7 @@ pub fn preprocess(pattern: String) -> Vec<usize> {
         preprocessed.push(0)
     }
     preprocessed[0] = pattern.len();
-    preprocessed[1] = match_length(&pattern, 0, 1);
+    preprocessed[1] = match_length(pattern, 0, 1);
     for i in 2..(1+preprocessed[1]) {
         preprocessed[i] = preprocessed[1] + 1 - i;
     }
@@ -48,36 +49,37 @@ pub fn preprocess(pattern: String) -> Vec<usize> {
                 preprocessed[i] = existing
             }
             else {
-                preprocessed[i] = match_length(&pattern, offset_from_upper, upper_b + 1);
-                if preprocessed[i] > 0 {
-                    lower_b = i;
-                    upper_b = i + preprocessed[i] - 1;
-                }
+                preprocessed[i] = offset_from_upper + match_length(pattern, offset_from_upper, upper_b + 1);
+                lower_b = i;
+                upper_b = i + preprocessed[i] - 1;
+            }
+        }
+        else {
+            preprocessed[i] = match_length(pattern, 0, i);
+            if preprocessed[i] > 0 {
+                lower_b = i;
+                upper_b = i + preprocessed[i] - 1;
             }
         }
     }
     return preprocessed;
 }
 
-pub fn bad_char_table(pattern: String) -> HashMap<char, usize> {
+// Rightmost position of each byte in the pattern
+pub fn bad_char_table(pattern: &[u8]) -> HashMap<u8, usize> {
     let mut table = HashMap::new();
-    for (i, c) in pattern.chars().enumerate(){
-        let mut offset = 1;
-        let temp_offset = pattern.len()-i-1;
-        if temp_offset > offset{
-            offset = temp_offset;
-        }
-        table.insert(c, offset);
+    for (i, c) in pattern.iter().cloned().enumerate(){
+        table.insert(c, i);
     }
     return table;
 }
 
-pub fn good_suff_table(pattern: String) -> Vec<i32> {
+pub fn good_suff_table(pattern: &[u8]) -> Vec<i32> {
     let mut l:Vec<i32> = Vec::new();
     for _ in 0..pattern.len() {
         l.push(-1);
     }
-    let mut preproc = preprocess(pattern.chars().rev().collect::<String>());
+    let mut preproc = preprocess(&pattern.iter().rev().cloned().collect::<Vec<u8>>());
     preproc.reverse();
     let mut i:usize;
     for j in 0..(pattern.len() - 1) {
@@ -89,7 +91,7 @@ pub fn good_suff_table(pattern: String) -> Vec<i32> {
     return l;
 }
 
-pub fn full_shift_table(pattern: String) -> Vec<usize> {
+pub fn full_shift_table(pattern: &[u8]) -> Vec<usize> {
     let mut f:Vec<usize> = Vec::new();
     for _ in 0..pattern.len() {
         f.push(0)
@@ -107,65 +109,65 @@ pub fn full_shift_table(pattern: String) -> Vec<usize> {
     return f
 }
 
+// Returns the byte offset just past every occurrence of `pattern` in
+// `source`, overlapping occurrences included
 pub fn string_search(source: &str, pattern: &String) -> Vec<usize>{
+    return search(source, pattern, false);
+}
+
+// Like `string_search`, but ASCII letters match in either case
+pub fn string_search_ignore_case(source: &str, pattern: &String) -> Vec<usize>{
+    return search(source, pattern, true);
+}
+
+fn search(source: &str, pattern: &String, ignore_case: bool) -> Vec<usize>{
     let mut indices: Vec<usize> = Vec::new();
-    if source.len() == 0 || pattern.len() == 0 || source.len() < pattern.len(){
+    if source.is_empty() || pattern.is_empty() || source.len() < pattern.len(){
         return indices;
     }
-    let bad_char = bad_char_table(pattern.clone().to_string());
-    let good_suff = good_suff_table(pattern.clone().to_string());
-    let full = full_shift_table(pattern.clone().to_string());
-    let mut idx = (pattern.len() - 1) as i32;
-    let mut previdx = None;
+    // Both sides are lowercased byte by byte, which leaves the bytes of
+    // multi-byte characters alone
+    let fold = |b: u8| if ignore_case { b.to_ascii_lowercase() } else { b };
+    let pattern_b: Vec<u8> = pattern.bytes().map(fold).collect();
+    let bad_char = bad_char_table(&pattern_b);
+    let good_suff = good_suff_table(&pattern_b);
+    let full = full_shift_table(&pattern_b);
+    let n = pattern.len() as i32;
+    let mut idx = n - 1;
 
-    let pattern_b = pattern.as_bytes();
     let source_b = source.as_bytes();
     while idx < source.len() as i32{
-        let mut i = (pattern.len() - 1) as i32;
+        let mut i = n - 1;
         let mut j = idx;
-        while i >= 0 && (pattern_b[i as usize] as char) == (source_b[j as usize] as char){
-            if previdx.is_none() || (previdx.is_some() && j > previdx.unwrap()){
-                i -= 1;
-                j -= 1;
-            }
+        while i >= 0 && pattern_b[i as usize] == fold(source_b[j as usize]){
+            i -= 1;
+            j -= 1;
         }
-        if i == -1 || (previdx.is_some() && j == previdx.unwrap()) {
+        if i == -1 {
             indices.push(idx as usize + 1);
-            if pattern.len() > 1{
-                idx += pattern.len() as i32 - full[1] as i32;
+            if n > 1{
+                idx += n - full[1] as i32;
             }
             else{
                 idx += 1;
             }
         }
         else{
-            let bad = bad_char.get(&(source_b[j as usize] as char));
-            let char_shift:i32;
-            if bad.is_none(){
-                char_shift = i + 1;
-            }
-            else{
-                char_shift = i - *bad.unwrap() as i32;
-            }
-            let suffix_shift:i32;
-            if i + 1 == pattern.len() as i32{
-                suffix_shift = 1;
+            let char_shift = match bad_char.get(&fold(source_b[j as usize])) {
+                None => i + 1,
+                Some(bad) => i - *bad as i32,
+            };
+            let suffix_shift = if i + 1 == n{
+                1
             }
             else if good_suff[i as usize + 1] == -1{
-                suffix_shift = pattern.len() as i32 - full[i as usize + 1] as i32
+                n - full[i as usize + 1] as i32
             }
             else{
-                suffix_shift = pattern.len() as i32 - good_suff[i as usize + 1] as i32
-            }
-            let mut shift = char_shift;
-            if shift < suffix_shift{
-                shift = suffix_shift;
-            }
-            if shift >= i + 1{
-                previdx = Some(idx);
-            }
-            idx += shift;
+                n - 1 - good_suff[i as usize + 1]
+            };
+            idx += char_shift.max(suffix_shift).max(1);
         }
     }
     return indices;
-}
\ No newline at end of file
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+
+pub const USAGE: &str = "usage: grep [OPTION]... PATTERN [FILE]...
+       grep [OPTION]... -e PATTERN... [FILE]...
+       grep [OPTION]... -f PATTERN_FILE... [FILE]...";
+
+pub const HELP: &str = "Search for PATTERN in each FILE. When FILE is -, or there is no FILE, read
+standard input; with -r and no FILE, search the working directory.
+
+Pattern selection:
+  -e, --regexp=PATTERN      use PATTERN for matching, may be repeated
+  -f, --file=FILE           take patterns from FILE, one per line
+  -i, --ignore-case         ignore case distinctions
+  -w, --word-regexp         match only whole words
+  -x, --line-regexp         match only whole lines
+  -U, --multiline           let matches span lines: \\n, [^...] and other
+                            complements match a newline, and so does '.'
+                            with (?s); a match is printed from the line it
+                            starts on through the line it ends on, and may
+                            run over at most 64 MiB of lines
+      --posix-classes       use the old \\s, ASCII letters, and \\w, tab and
+                            space, instead of Perl's whitespace and word
+                            characters
+
+Output control:
+  -v, --invert-match        select non-matching lines
+  -c, --count               print only a count of selected lines per file
+  -l, --files-with-matches  print only the names of files with selected lines
+  -L, --files-without-match print only the names of files with no selected lines
+  -n, --line-number         print the line number with each output line
+  -o, --only-matching       print only the matched parts of a line
+  -H, --with-filename       print the file name for each match
+  -h, --no-filename         never print file names
+  -q, --quiet, --silent     print nothing, exit 0 at the first selected line
+      --json                print a JSON object per line found, see README.md
+      --color[=WHEN]        color matches, file names, line numbers and
+                            separators; WHEN is never, always or auto, which
+                            colors only a terminal and is the default for a
+                            bare --color; GREP_COLORS changes the colors
+
+Context control:
+  -A, --after-context=NUM   print NUM lines after each selected line
+  -B, --before-context=NUM  print NUM lines before each selected line
+  -C, --context=NUM         print NUM lines before and after, unless -A or -B
+                            says otherwise
+
+File selection:
+  -r, --recursive           search directories recursively, following only
+                            the symlinks named on the command line
+  -R, --dereference-recursive
+                            search directories recursively, following every
+                            symlink
+      --include=GLOB        search only files whose name matches GLOB
+      --exclude=GLOB        skip files whose name matches GLOB
+
+Miscellaneous:
+      --stats               print what the pattern compiles to and exit
+      --help                display this help and exit
+      --version             display version information and exit
+
+The exit status is 0 if a line is selected, 1 if none is, and 2 if an error
+occurred, unless -q is given and a line was selected.";
+
+// When to color output, with --color
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    #[default]
+    Never,
+    // Only when writing to a terminal
+    Auto,
+    Always,
+}
+
+// Everything the command line asks for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    // Patterns from the first operand or from -e, a line matches if any does
+    pub patterns: Vec<String>,
+    // Files to read more patterns from with -f
+    pub pattern_files: Vec<String>,
+    pub files: Vec<String>,
+    pub recursive: bool,
+    // -R, follow symlinks met inside directories too and not only those
+    // given as operands
+    pub follow_symlinks: bool,
+    // -H or -h, otherwise names are printed when there may be several files
+    pub with_filename: Option<bool>,
+    // Globs a file's name must match one of, if any, and may match none of
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub ignore_case: bool,
+    pub word: bool,
+    pub whole_line: bool,
+    // -U, let the pattern match newlines as if it began with (?m)
+    pub multiline: bool,
+    // Keep the old \s and \w, letters and tab or space
+    pub posix_classes: bool,
+    pub invert: bool,
+    pub count: bool,
+    pub files_with_matches: bool,
+    pub files_without_match: bool,
+    pub line_number: bool,
+    pub only_matching: bool,
+    // -q, print nothing and stop at the first selected line
+    pub quiet: bool,
+    // Lines to print around selected ones with -A and -B, or -C for both
+    pub after_context: Option<usize>,
+    pub before_context: Option<usize>,
+    pub context: Option<usize>,
+    pub color: ColorChoice,
+    // Print JSON Lines events instead of text
+    pub json: bool,
+    pub stats: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Version,
+    Search(Options),
+}
+
+// A command line that cannot be run, reported with the usage text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageError {
+    pub message: String,
+}
+
+impl UsageError {
+    fn new(message: String) -> Self {
+        UsageError { message }
+    }
+}
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+// Long options without a value and the short option each one stands for
+const LONG_FLAGS: [(&str, char); 16] = [
+    ("recursive", 'r'),
+    ("dereference-recursive", 'R'),
+    ("with-filename", 'H'),
+    ("no-filename", 'h'),
+    ("ignore-case", 'i'),
+    ("word-regexp", 'w'),
+    ("line-regexp", 'x'),
+    ("multiline", 'U'),
+    ("invert-match", 'v'),
+    ("count", 'c'),
+    ("files-with-matches", 'l'),
+    ("files-without-match", 'L'),
+    ("line-number", 'n'),
+    ("only-matching", 'o'),
+    ("quiet", 'q'),
+    ("silent", 'q'),
+];
+
+// Options that take a value, `--name=value` or `--name value`, and the short
+// option for the ones that have one
+const LONG_VALUES: [&str; 7] = ["regexp", "file", "include", "exclude", "after-context", "before-context", "context"];
+const SHORT_VALUES: [(char, &str); 5] = [
+    ('e', "regexp"),
+    ('f', "file"),
+    ('A', "after-context"),
+    ('B', "before-context"),
+    ('C', "context"),
+];
+
+fn set_flag(options: &mut Options, flag: char) {
+    match flag {
+        'r' => options.recursive = true,
+        'R' => {
+            options.recursive = true;
+            options.follow_symlinks = true;
+        }
+        'H' => options.with_filename = Some(true),
+        'h' => options.with_filename = Some(false),
+        'i' => options.ignore_case = true,
+        'w' => options.word = true,
+        'x' => options.whole_line = true,
+        'U' => options.multiline = true,
+        'v' => options.invert = true,
+        'c' => options.count = true,
+        'l' => options.files_with_matches = true,
+        'L' => options.files_without_match = true,
+        'n' => options.line_number = true,
+        'o' => options.only_matching = true,
+        'q' => options.quiet = true,
+        _ => {}
+    }
+}
+
+fn set_value(options: &mut Options, option: &str, value: String) -> Result<(), UsageError> {
+    let lines = || match value.parse::<usize>() {
+        Ok(lines) => Ok(Some(lines)),
+        Err(_) => Err(UsageError::new(format!("{}: invalid context length argument", value))),
+    };
+    match option {
+        "after-context" => options.after_context = lines()?,
+        "before-context" => options.before_context = lines()?,
+        "context" => options.context = lines()?,
+        "regexp" => options.patterns.push(value),
+        "file" => options.pattern_files.push(value),
+        "include" => options.include.push(value),
+        "exclude" => options.exclude.push(value),
+        _ => {}
+    }
+    return Ok(());
+}
+
+// Parses the arguments after the program name. Short options may be grouped
+// (`-in`) and take their value attached or as the next argument (`-eFOO`,
+// `-e FOO`); `--` ends the options.
+pub fn parse_args(args: &[String]) -> Result<Command, UsageError> {
+    let mut options = Options::default();
+    let mut operands: Vec<String> = Vec::new();
+    let mut help = false;
+    let mut version = false;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            operands.extend(args.by_ref().cloned());
+        }
+        else if let Some(long) = arg.strip_prefix("--") {
+            let (name, attached) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let flag = LONG_FLAGS.iter().find(|(long, _)| *long == name).map(|(_, flag)| *flag);
+            // The value is optional, so it can only be attached
+            if name == "color" || name == "colour" {
+                options.color = match attached.as_deref() {
+                    None | Some("auto") | Some("tty") | Some("if-tty") => ColorChoice::Auto,
+                    Some("always") | Some("yes") | Some("force") => ColorChoice::Always,
+                    Some("never") | Some("no") | Some("none") => ColorChoice::Never,
+                    Some(value) => return Err(UsageError::new(format!("invalid argument '{}' for '--{}'", value, name))),
+                };
+            }
+            else if ["posix-classes", "stats", "json", "help", "version"].contains(&name) || flag.is_some() {
+                if attached.is_some() {
+                    return Err(UsageError::new(format!("option '--{}' doesn't allow an argument", name)));
+                }
+                match name {
+                    "posix-classes" => options.posix_classes = true,
+                    "stats" => options.stats = true,
+                    "json" => options.json = true,
+                    "help" => help = true,
+                    "version" => version = true,
+                    _ => set_flag(&mut options, flag.unwrap()),
+                }
+            }
+            else if LONG_VALUES.contains(&name) {
+                let value = match attached.or_else(|| args.next().cloned()) {
+                    Some(value) => value,
+                    None => return Err(UsageError::new(format!("option '--{}' requires an argument", name))),
+                };
+                set_value(&mut options, name, value)?;
+            }
+            else {
+                return Err(UsageError::new(format!("unrecognized option '{}'", arg)));
+            }
+        }
+        else if arg.len() > 1 && arg.starts_with('-') {
+            for (i, flag) in arg[1..].char_indices() {
+                match flag {
+                    'r' | 'R' | 'H' | 'h' | 'i' | 'w' | 'x' | 'U' | 'v' | 'c' | 'l' | 'L' | 'n' | 'o' | 'q' => set_flag(&mut options, flag),
+                    'e' | 'f' | 'A' | 'B' | 'C' => {
+                        // The rest of the argument is the value if there is any
+                        let rest = &arg[1 + i + flag.len_utf8()..];
+                        let value = if !rest.is_empty() { Some(rest.to_string()) } else { args.next().cloned() };
+                        match value {
+                            Some(value) => {
+                                let (_, name) = SHORT_VALUES.iter().find(|(short, _)| *short == flag).unwrap();
+                                set_value(&mut options, name, value)?;
+                            }
+                            None => return Err(UsageError::new(format!("option requires an argument -- '{}'", flag))),
+                        }
+                        break;
+                    }
+                    _ => return Err(UsageError::new(format!("invalid option -- '{}'", flag))),
+                }
+            }
+        }
+        else {
+            operands.push(arg.clone());
+        }
+    }
+
+    if help {
+        return Ok(Command::Help);
+    }
+    if version {
+        return Ok(Command::Version);
+    }
+    // Without -e or -f the first operand is the pattern
+    let mut operands = operands.into_iter();
+    if options.patterns.is_empty() && options.pattern_files.is_empty() {
+        match operands.next() {
+            Some(pattern) => options.patterns.push(pattern),
+            None => return Err(UsageError::new("no pattern given".to_string())),
+        }
+    }
+    if options.json && (options.count || options.files_with_matches || options.files_without_match || options.quiet) {
+        return Err(UsageError::new("--json cannot be used with -c, -l, -L or -q".to_string()));
+    }
+    // No file means standard input, or the working directory with -r
+    options.files = operands.collect();
+    return Ok(Command::Search(options));
+}
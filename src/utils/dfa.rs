@@ -0,0 +1,579 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
+
+use super::state_utils::*;
+
+// Index of the state with no way to a match, every DFA has it at 0
+pub const DEAD: usize = 0;
+
+// What is known about the text before a position, which decides the
+// assertions a DFA can follow there. Once a character has been consumed \A
+// cannot hold, and ^ only can right after a newline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Context {
+    TextStart,
+    LineStart,
+    Mid,
+}
+
+impl Context {
+    pub fn at(text: &str, pos: usize) -> Context {
+        match text[..pos].chars().next_back() {
+            None => Context::TextStart,
+            Some('\n') => Context::LineStart,
+            Some(_) => Context::Mid,
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Context::TextStart => 0,
+            Context::LineStart => 1,
+            Context::Mid => 2,
+        }
+    }
+
+    fn allows(&self, assertion: Assertion) -> bool {
+        match assertion {
+            Assertion::TextStart => *self == Context::TextStart,
+            Assertion::LineStart => *self != Context::Mid,
+            // Looking ahead is left to the accept flags
+            Assertion::LineEnd | Assertion::TextEnd => false,
+        }
+    }
+}
+
+// Characters that no NFA transition tells apart share a class, so the DFA
+// only needs one column per class. Class i holds the characters from
+// boundaries[i - 1] up to but not including boundaries[i]. The newline always
+// has a class of its own, since ^ can hold after it.
+#[derive(Debug, Clone)]
+pub struct Alphabet {
+    boundaries: Vec<u32>,
+    ascii: Vec<usize>,
+}
+
+impl Alphabet {
+    pub fn new(states: &[State]) -> Alphabet {
+        let mut boundaries: BTreeSet<u32> = BTreeSet::from(['\n' as u32, '\n' as u32 + 1]);
+        for state in states.iter() {
+            for transition in state.out.iter() {
+                if let Some((lo, hi)) = transition.range {
+                    boundaries.insert(lo as u32);
+                    boundaries.insert(hi as u32 + 1);
+                }
+            }
+        }
+        let boundaries: Vec<u32> = boundaries.into_iter().collect();
+        let ascii = (0..128).map(|b| boundaries.partition_point(|&x| x <= b)).collect();
+        return Alphabet { boundaries, ascii };
+    }
+
+    fn len(&self) -> usize {
+        self.boundaries.len() + 1
+    }
+
+    pub fn class_of(&self, c: char) -> usize {
+        if c.is_ascii() {
+            return self.ascii[c as usize];
+        }
+        return self.boundaries.partition_point(|&x| x <= c as u32);
+    }
+
+    // The first character of a class, standing in for all of it
+    fn representative(&self, class: usize) -> u32 {
+        if class == 0 { 0 } else { self.boundaries[class - 1] }
+    }
+}
+
+// When a DFA state accepts: unconditionally, or only if the line or the whole
+// text ends right after it, for patterns ending in $ or \z
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Accept {
+    pub always: bool,
+    pub line_end: bool,
+    pub text_end: bool,
+}
+
+impl Accept {
+    pub fn holds(&self, next: Option<char>) -> bool {
+        match next {
+            None => self.always || self.line_end || self.text_end,
+            Some('\n') => self.always || self.line_end,
+            Some(_) => self.always,
+        }
+    }
+}
+
+// A DFA state before numbering: the NFA states it stands for, and for the
+// unanchored automaton the context in which a fresh thread joins them before
+// the next character
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StateSet {
+    pub nfa_states: Vec<usize>,
+    pub restart: Option<Context>,
+}
+
+// Adds every state reachable through tags and the assertions `follow` accepts
+fn closure(set: &mut BTreeSet<usize>, states: &[State], follow: &dyn Fn(Option<Assertion>) -> bool) {
+    let mut stack: Vec<usize> = set.iter().cloned().collect();
+    while let Some(state) = stack.pop() {
+        for transition in states[state].out.iter() {
+            if transition.range.is_some() || !follow(transition.assertion) {
+                continue;
+            }
+            let next = transition.state.unwrap();
+            if set.insert(next) {
+                stack.push(next);
+            }
+        }
+    }
+}
+
+fn forward_closure(set: &mut BTreeSet<usize>, states: &[State], context: Context) {
+    closure(set, states, &|assertion| assertion.is_none_or(|a| context.allows(a)));
+}
+
+// The set of NFA states a DFA starts in. An anchored automaton only tries a
+// match at the position it starts from, an unanchored one at every position.
+pub fn start_set(states: &[State], start: usize, anchored: bool, context: Context) -> StateSet {
+    if !anchored {
+        return StateSet { nfa_states: Vec::new(), restart: Some(context) };
+    }
+    let mut set = BTreeSet::from([start]);
+    forward_closure(&mut set, states, context);
+    return StateSet { nfa_states: set.into_iter().collect(), restart: None };
+}
+
+// The set reached from `from` by consuming a character of `class`
+pub fn next_set(states: &[State], start: usize, alphabet: &Alphabet, from: &StateSet, class: usize) -> StateSet {
+    let mut current: BTreeSet<usize> = from.nfa_states.iter().cloned().collect();
+    if let Some(context) = from.restart {
+        let mut fresh = BTreeSet::from([start]);
+        forward_closure(&mut fresh, states, context);
+        current.extend(fresh);
+    }
+    let c = alphabet.representative(class);
+    // $ holds right before a newline, which multiline mode can go on past
+    if c == '\n' as u32 {
+        closure(&mut current, states, &|assertion| matches!(assertion, None | Some(Assertion::LineEnd)));
+    }
+    let mut next: BTreeSet<usize> = BTreeSet::new();
+    for state in current.iter() {
+        for transition in states[*state].out.iter() {
+            if transition.range.is_some_and(|(lo, hi)| lo as u32 <= c && c <= hi as u32) {
+                next.insert(transition.state.unwrap());
+            }
+        }
+    }
+    let context = if c == '\n' as u32 { Context::LineStart } else { Context::Mid };
+    forward_closure(&mut next, states, context);
+    let restart = from.restart.map(|_| context);
+    return StateSet { nfa_states: next.into_iter().collect(), restart };
+}
+
+pub fn accept_of(states: &[State], set: &StateSet) -> Accept {
+    let reaches_match = |text_end: bool| {
+        let mut reached: BTreeSet<usize> = set.nfa_states.iter().cloned().collect();
+        closure(&mut reached, states, &|assertion| match assertion {
+            None | Some(Assertion::LineEnd) => true,
+            Some(Assertion::TextEnd) => text_end,
+            Some(_) => false,
+        });
+        reached.iter().any(|s| states[*s].is_match)
+    };
+    return Accept {
+        always: set.nfa_states.iter().any(|s| states[*s].is_match),
+        line_end: reaches_match(false),
+        text_end: reaches_match(true),
+    };
+}
+
+// A lazy DFA stops when its cache keeps filling up before much text has been
+// scanned, simulating the NFA is likely to be faster from then on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GaveUp;
+
+// What the searches below need from a DFA
+pub trait Automaton {
+    fn start(&self, context: Context) -> Result<usize, GaveUp>;
+    fn next(&self, state: usize, c: char) -> Result<usize, GaveUp>;
+    fn accepts(&self, state: usize, next: Option<char>) -> bool;
+}
+
+// A DFA built up front by subset construction over an epsilon-free NFA.
+// Captures are not tracked; tags are followed like epsilon transitions.
+#[derive(Debug, Clone)]
+pub struct Dfa {
+    alphabet: Alphabet,
+    // table[state * alphabet.len() + class] is the next state
+    table: Vec<usize>,
+    accept: Vec<Accept>,
+    // Start state for each Context
+    starts: [usize; 3],
+}
+
+impl Dfa {
+    // Returns None if the DFA would need more than `state_limit` states
+    pub fn build(states: &[State], start: usize, anchored: bool, state_limit: usize) -> Option<Dfa> {
+        let alphabet = Alphabet::new(states);
+        let classes = alphabet.len();
+        let mut sets: Vec<StateSet> = vec![dead_set()];
+        let mut ids: HashMap<StateSet, usize> = HashMap::from([(dead_set(), DEAD)]);
+        let mut starts = [DEAD; 3];
+        for context in [Context::TextStart, Context::LineStart, Context::Mid] {
+            let set = start_set(states, start, anchored, context);
+            starts[context.index()] = *ids.entry(set.clone()).or_insert_with(|| {
+                sets.push(set);
+                sets.len() - 1
+            });
+        }
+
+        let mut table: Vec<usize> = Vec::new();
+        let mut i = 0;
+        while i < sets.len() {
+            for class in 0..classes {
+                let next = if i == DEAD { dead_set() } else { next_set(states, start, &alphabet, &sets[i], class) };
+                let id = match ids.get(&next) {
+                    Some(id) => *id,
+                    None => {
+                        if sets.len() >= state_limit {
+                            return None;
+                        }
+                        ids.insert(next.clone(), sets.len());
+                        sets.push(next);
+                        sets.len() - 1
+                    }
+                };
+                table.push(id);
+            }
+            i += 1;
+        }
+        let accept = sets.iter().map(|set| accept_of(states, set)).collect();
+        return Some(Dfa { alphabet, table, accept, starts });
+    }
+
+    pub fn len(&self) -> usize {
+        self.accept.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accept.is_empty()
+    }
+
+    // Merges states that no text can tell apart, using Hopcroft's partition
+    // refinement. States start out split by how they accept, then a block is
+    // split whenever some of its states move into a given block on a class
+    // and others do not.
+    pub fn minimize(&self) -> Dfa {
+        let classes = self.alphabet.len();
+        let n = self.len();
+
+        // inverse[class][state] lists the states that move to `state` on `class`
+        let mut inverse: Vec<Vec<Vec<usize>>> = vec![vec![Vec::new(); n]; classes];
+        for state in 0..n {
+            for class in 0..classes {
+                inverse[class][self.table[state * classes + class]].push(state);
+            }
+        }
+
+        let mut block_of: Vec<usize> = vec![0; n];
+        let mut blocks: Vec<Vec<usize>> = Vec::new();
+        let mut by_accept: HashMap<Accept, usize> = HashMap::new();
+        for state in 0..n {
+            let block = *by_accept.entry(self.accept[state]).or_insert_with(|| {
+                blocks.push(Vec::new());
+                blocks.len() - 1
+            });
+            block_of[state] = block;
+            blocks[block].push(state);
+        }
+
+        let mut pending: Vec<usize> = (0..blocks.len()).collect();
+        let mut is_pending: Vec<bool> = vec![true; blocks.len()];
+        while let Some(splitter) = pending.pop() {
+            is_pending[splitter] = false;
+            let members = blocks[splitter].clone();
+            for class in 0..classes {
+                // The states moving into the splitter, grouped by their block
+                let mut touched: HashMap<usize, Vec<usize>> = HashMap::new();
+                for target in members.iter() {
+                    for source in inverse[class][*target].iter() {
+                        touched.entry(block_of[*source]).or_default().push(*source);
+                    }
+                }
+                let mut touched: Vec<(usize, Vec<usize>)> = touched.into_iter().collect();
+                touched.sort();
+                for (block, moving) in touched {
+                    if moving.len() == blocks[block].len() {
+                        continue;
+                    }
+                    let new_block = blocks.len();
+                    let moving_set: BTreeSet<usize> = moving.into_iter().collect();
+                    let (inside, outside): (Vec<usize>, Vec<usize>) = blocks[block].iter().partition(|s| moving_set.contains(s));
+                    for state in outside.iter() {
+                        block_of[*state] = new_block;
+                    }
+                    blocks[block] = inside;
+                    blocks.push(outside);
+                    // Splitting on either half does the work of the whole
+                    // block, so unless the block is still pending only the
+                    // smaller half needs to be queued
+                    is_pending.push(false);
+                    let added = if is_pending[block] || blocks[new_block].len() <= blocks[block].len() { new_block } else { block };
+                    is_pending[added] = true;
+                    pending.push(added);
+                }
+            }
+        }
+
+        // Renumber the blocks, keeping the dead state's block at DEAD
+        let mut number: Vec<usize> = vec![usize::MAX; blocks.len()];
+        number[block_of[DEAD]] = DEAD;
+        let mut next_number = 1;
+        for state in 0..n {
+            let block = block_of[state];
+            if number[block] == usize::MAX {
+                number[block] = next_number;
+                next_number += 1;
+            }
+        }
+        let mut table: Vec<usize> = vec![DEAD; next_number * classes];
+        let mut accept: Vec<Accept> = vec![Accept::default(); next_number];
+        for state in 0..n {
+            let new_state = number[block_of[state]];
+            accept[new_state] = self.accept[state];
+            for class in 0..classes {
+                table[new_state * classes + class] = number[block_of[self.table[state * classes + class]]];
+            }
+        }
+        let starts = self.starts.map(|start| number[block_of[start]]);
+        return Dfa { alphabet: self.alphabet.clone(), table, accept, starts };
+    }
+}
+
+impl Automaton for Dfa {
+    fn start(&self, context: Context) -> Result<usize, GaveUp> {
+        Ok(self.starts[context.index()])
+    }
+
+    fn next(&self, state: usize, c: char) -> Result<usize, GaveUp> {
+        Ok(self.table[state * self.alphabet.len() + self.alphabet.class_of(c)])
+    }
+
+    fn accepts(&self, state: usize, next: Option<char>) -> bool {
+        self.accept[state].holds(next)
+    }
+}
+
+// Marks a transition or start state the lazy DFA has not worked out yet
+const UNKNOWN: usize = usize::MAX;
+// Rough bytes a cached state costs on top of its row and NFA state list
+const STATE_OVERHEAD: usize = 64;
+// A flush is a bad one if fewer characters than this per cached state were
+// scanned since the last; after MAX_BAD_FLUSHES of them the DFA gives up
+const MIN_CHARS_PER_STATE: usize = 10;
+const MAX_BAD_FLUSHES: usize = 3;
+
+#[derive(Debug, Clone)]
+struct Cache {
+    sets: Vec<StateSet>,
+    ids: HashMap<StateSet, usize>,
+    table: Vec<usize>,
+    accept: Vec<Accept>,
+    starts: [usize; 3],
+    memory: usize,
+    // Characters scanned since the last flush
+    scanned: usize,
+    bad_flushes: usize,
+    // Bumped by every flush
+    generation: usize,
+}
+
+impl Cache {
+    fn new(classes: usize) -> Cache {
+        let mut cache = Cache {
+            sets: Vec::new(),
+            ids: HashMap::new(),
+            table: Vec::new(),
+            accept: Vec::new(),
+            starts: [UNKNOWN; 3],
+            memory: 0,
+            scanned: 0,
+            bad_flushes: 0,
+            generation: 0,
+        };
+        cache.clear(classes);
+        return cache;
+    }
+
+    // Empties the cache down to the dead state
+    fn clear(&mut self, classes: usize) {
+        self.sets = vec![dead_set()];
+        self.ids = HashMap::from([(dead_set(), DEAD)]);
+        self.table = vec![DEAD; classes];
+        self.accept = vec![Accept::default()];
+        self.starts = [UNKNOWN; 3];
+        self.memory = state_cost(classes, &dead_set());
+        self.scanned = 0;
+        self.generation += 1;
+    }
+}
+
+fn state_cost(classes: usize, set: &StateSet) -> usize {
+    (classes + 2 * set.nfa_states.len()) * std::mem::size_of::<usize>() + STATE_OVERHEAD
+}
+
+// A DFA whose states are determinized as the text reaches them and kept in a
+// cache of bounded size, for patterns whose full DFA would be too large.
+// The cache is flushed whenever it reaches `cache_limit` bytes.
+#[derive(Debug, Clone)]
+pub struct LazyDfa {
+    nfa: Rc<[State]>,
+    start: usize,
+    anchored: bool,
+    alphabet: Alphabet,
+    cache_limit: usize,
+    cache: RefCell<Box<Cache>>,
+}
+
+impl LazyDfa {
+    pub fn new(nfa: Rc<[State]>, start: usize, anchored: bool, cache_limit: usize) -> LazyDfa {
+        let alphabet = Alphabet::new(&nfa);
+        let cache = RefCell::new(Box::new(Cache::new(alphabet.len())));
+        LazyDfa { nfa, start, anchored, alphabet, cache_limit, cache }
+    }
+
+    // States currently cached, the dead state included
+    pub fn cached_states(&self) -> usize {
+        self.cache.borrow().sets.len()
+    }
+
+    pub fn gave_up(&self) -> bool {
+        self.cache.borrow().bad_flushes >= MAX_BAD_FLUSHES
+    }
+
+    fn add_state(&self, cache: &mut Cache, set: StateSet) -> Result<usize, GaveUp> {
+        if let Some(id) = cache.ids.get(&set) {
+            return Ok(*id);
+        }
+        let classes = self.alphabet.len();
+        let cost = state_cost(classes, &set);
+        if cache.memory + cost > self.cache_limit {
+            if cache.scanned < MIN_CHARS_PER_STATE * cache.sets.len() {
+                cache.bad_flushes += 1;
+            }
+            if cache.bad_flushes >= MAX_BAD_FLUSHES {
+                return Err(GaveUp);
+            }
+            cache.clear(classes);
+        }
+        let id = cache.sets.len();
+        cache.memory += cost;
+        cache.accept.push(accept_of(&self.nfa, &set));
+        cache.ids.insert(set.clone(), id);
+        cache.sets.push(set);
+        cache.table.extend(std::iter::repeat_n(UNKNOWN, classes));
+        return Ok(id);
+    }
+}
+
+impl Automaton for LazyDfa {
+    fn start(&self, context: Context) -> Result<usize, GaveUp> {
+        let mut cache = self.cache.borrow_mut();
+        if cache.bad_flushes >= MAX_BAD_FLUSHES {
+            return Err(GaveUp);
+        }
+        let id = cache.starts[context.index()];
+        if id != UNKNOWN {
+            return Ok(id);
+        }
+        let set = start_set(&self.nfa, self.start, self.anchored, context);
+        let id = self.add_state(&mut cache, set)?;
+        cache.starts[context.index()] = id;
+        return Ok(id);
+    }
+
+    fn next(&self, state: usize, c: char) -> Result<usize, GaveUp> {
+        let mut cache = self.cache.borrow_mut();
+        cache.scanned += 1;
+        let class = self.alphabet.class_of(c);
+        let slot = state * self.alphabet.len() + class;
+        if cache.table[slot] != UNKNOWN {
+            return Ok(cache.table[slot]);
+        }
+        let set = next_set(&self.nfa, self.start, &self.alphabet, &cache.sets[state], class);
+        let generation = cache.generation;
+        let id = self.add_state(&mut cache, set)?;
+        // A flush renumbers every state, `state` included, so its row is gone
+        if cache.generation == generation {
+            cache.table[slot] = id;
+        }
+        return Ok(id);
+    }
+
+    fn accepts(&self, state: usize, next: Option<char>) -> bool {
+        self.cache.borrow().accept[state].holds(next)
+    }
+}
+
+// End of the longest non-empty match starting at `from`, for an anchored DFA
+pub fn longest_match<A: Automaton>(dfa: &A, text: &str, from: usize) -> Result<Option<usize>, GaveUp> {
+    let mut state = dfa.start(Context::at(text, from))?;
+    let mut last = None;
+    let mut i = from;
+    let mut chars = text[from..].chars().peekable();
+    while let Some(c) = chars.next() {
+        state = dfa.next(state, c)?;
+        if state == DEAD {
+            break;
+        }
+        i += c.len_utf8();
+        if dfa.accepts(state, chars.peek().copied()) {
+            last = Some(i);
+        }
+    }
+    return Ok(last);
+}
+
+// Every end of a match starting at `from` for an anchored DFA, shortest
+// first, not counting an empty match
+pub fn match_ends<A: Automaton>(dfa: &A, text: &str, from: usize) -> Result<Vec<usize>, GaveUp> {
+    let mut state = dfa.start(Context::at(text, from))?;
+    let mut ends = Vec::new();
+    let mut i = from;
+    let mut chars = text[from..].chars().peekable();
+    while let Some(c) = chars.next() {
+        state = dfa.next(state, c)?;
+        if state == DEAD {
+            break;
+        }
+        i += c.len_utf8();
+        if dfa.accepts(state, chars.peek().copied()) {
+            ends.push(i);
+        }
+    }
+    return Ok(ends);
+}
+
+// End of the first match to finish, starting at or after `from`, for an
+// unanchored DFA. The leftmost-longest match starts no later than that match
+// does, and on the same line unless the pattern can match a newline.
+pub fn earliest_match_end<A: Automaton>(dfa: &A, text: &str, from: usize) -> Result<Option<usize>, GaveUp> {
+    let mut state = dfa.start(Context::at(text, from))?;
+    let mut i = from;
+    let mut chars = text[from..].chars().peekable();
+    while let Some(c) = chars.next() {
+        i += c.len_utf8();
+        state = dfa.next(state, c)?;
+        if dfa.accepts(state, chars.peek().copied()) {
+            return Ok(Some(i));
+        }
+    }
+    return Ok(None);
+}
+
+fn dead_set() -> StateSet {
+    StateSet { nfa_states: Vec::new(), restart: None }
+}
@@ -31,10 +31,13 @@ pub type Terminal = char;
 pub type NonTerminal = String;
 
 /// A sequence of `Symbol`s forms the right-hand-side of a CFG production.
+/// A `Range` matches any one terminal between its bounds, inclusive, and
+/// shows up in the parse tree as the `Terminal` it matched.
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Symbol {
     Terminal(Terminal),
     NonTerminal(NonTerminal),
+    Range(Terminal, Terminal),
 }
 
 impl Symbol {
@@ -42,6 +45,7 @@ impl Symbol {
         match self {
             Symbol::Terminal(ref c) => c.to_string(),
             Symbol::NonTerminal(ref s) => s.clone(),
+            Symbol::Range(lo, hi) => format!("{}-{}", lo.escape_unicode(), hi.escape_unicode()),
         }
     }
 }
@@ -56,6 +60,12 @@ pub fn tr(x: Terminal) -> Symbol {
     Symbol::Terminal(x)
 }
 
+/// Convenience function for creating a `Symbol` matching any terminal from
+/// `lo` to `hi`
+pub fn tr_range(lo: Terminal, hi: Terminal) -> Symbol {
+    Symbol::Range(lo, hi)
+}
+
 /// A struct holding production rules for a CFG.
 pub struct CFG {
     start: NonTerminal,
@@ -77,7 +87,7 @@ impl CFG {
         let lhs: NonTerminal = lhs.into();
         self.rule_map
             .entry(lhs)
-            .or_insert_with(|| Vec::new())
+            .or_default()
             .push(rhs)
     }
 
@@ -90,6 +100,9 @@ impl CFG {
 pub struct ASTNode {
     pub sym: Symbol,
     pub children: Vec<ASTNode>,
+    /// Byte offsets of the input this node was parsed from.
+    pub start: usize,
+    pub end: usize,
 }
 
 impl ASTNode {
@@ -106,7 +119,7 @@ impl ASTNode {
 
     pub fn is_terminal(&self) -> bool {
         match self.sym {
-            Symbol::Terminal(_) => true,
+            Symbol::Terminal(_) | Symbol::Range(_, _) => true,
             Symbol::NonTerminal(_) => false,
         }
     }
@@ -214,8 +227,29 @@ impl cmp::PartialEq for EarleyState {
 
 impl cmp::Eq for EarleyState {}
 
+/// Where the input stopped fitting the grammar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Offset of the first character no parse could consume, or the input
+    /// length if the input ended too early.
+    pub offset: usize,
+    /// Terminals that would have let the parse continue at `offset`.
+    pub expected: BTreeSet<Terminal>,
+    /// Nonterminals partway through a rule at `offset`, which tells where in
+    /// the grammar the parse died.
+    pub context: BTreeSet<NonTerminal>,
+}
+
 /// Perform Earley parsing on the input using the given CFG.
 pub fn parse(input: &str, grammar: &CFG) -> Option<ASTNode> {
+    try_parse(input, grammar).ok()
+}
+
+/// Perform Earley parsing on the input using the given CFG, reporting where
+/// the chart died if the input is not in the language.
+/// The chart is indexed by byte offset; entries inside a multi-byte character
+/// stay empty.
+pub fn try_parse(input: &str, grammar: &CFG) -> Result<ASTNode, ParseError> {
     let mut mem = vec![BTreeSet::new(); input.len() + 1];
     for rhs in grammar.rules(&grammar.start) {
         mem[0].insert(Rc::new(EarleyState::new(
@@ -226,7 +260,8 @@ pub fn parse(input: &str, grammar: &CFG) -> Option<ASTNode> {
     }
 
     for i in 0..=input.len() {
-        let mut q = mem[i].iter().map(|s| s.clone()).collect::<VecDeque<_>>();
+        let next = input.get(i..).and_then(|rest| rest.chars().next());
+        let mut q = mem[i].iter().cloned().collect::<VecDeque<_>>();
         while let Some(curr_state) = q.pop_front() {
             if !curr_state.done() {
                 match curr_state.next_sym() {
@@ -243,14 +278,20 @@ pub fn parse(input: &str, grammar: &CFG) -> Option<ASTNode> {
                             }
                         }
                     }
-                    Symbol::Terminal(t) => {
+                    ref sym => {
                         // Scan
-                        if i < input.len() && t == input.as_bytes()[i] as char {
+                        let scanned = match (sym, next) {
+                            (Symbol::Terminal(t), Some(c)) => *t == c,
+                            (Symbol::Range(lo, hi), Some(c)) => *lo <= c && c <= *hi,
+                            _ => false,
+                        };
+                        if scanned {
+                            let after = i + next.unwrap().len_utf8();
                             let mut new_state = Rc::new(curr_state.advance());
-                            if !mem[i + 1].contains(&new_state) {
+                            if !mem[after].contains(&new_state) {
                                 Rc::get_mut(&mut new_state).unwrap().left_parent =
                                     Some(Rc::clone(&curr_state));
-                                mem[i + 1].insert(new_state);
+                                mem[after].insert(new_state);
                             }
                         }
                     }
@@ -259,7 +300,7 @@ pub fn parse(input: &str, grammar: &CFG) -> Option<ASTNode> {
                 // Complete
                 let iterlist = mem[curr_state.start_idx]
                     .iter()
-                    .map(|s| Rc::clone(s))
+                    .map(Rc::clone)
                     .collect::<Vec<_>>();
                 for state in iterlist.into_iter() {
                     if !state.done()
@@ -280,36 +321,67 @@ pub fn parse(input: &str, grammar: &CFG) -> Option<ASTNode> {
         }
     }
 
-    fn generate_parse_tree(state: Rc<EarleyState>) -> ASTNode {
+    // `end` is the input offset the completed `state` ends at
+    fn generate_parse_tree(input: &str, state: Rc<EarleyState>, end: usize) -> ASTNode {
         let mut iter = Rc::clone(&state);
         let mut children = Vec::new();
+        let mut child_end = end;
         for i in (0..state.rhs.len()).rev() {
             match state.rhs[i] {
-                Symbol::NonTerminal(_) => children.insert(
-                    0,
-                    generate_parse_tree(Rc::clone(iter.right_parent.as_ref().unwrap())),
-                ),
-                Symbol::Terminal(tt) => children.insert(
-                    0,
-                    ASTNode {
-                        sym: tr(tt),
-                        children: Vec::new(),
-                    },
-                ),
+                Symbol::NonTerminal(_) => {
+                    let child = Rc::clone(iter.right_parent.as_ref().unwrap());
+                    let child_start = child.start_idx;
+                    children.insert(0, generate_parse_tree(input, child, child_end));
+                    child_end = child_start;
+                }
+                Symbol::Terminal(_) | Symbol::Range(_, _) => {
+                    let tt = input[..child_end].chars().next_back().unwrap();
+                    children.insert(
+                        0,
+                        ASTNode {
+                            sym: tr(tt),
+                            children: Vec::new(),
+                            start: child_end - tt.len_utf8(),
+                            end: child_end,
+                        },
+                    );
+                    child_end -= tt.len_utf8();
+                }
             }
             iter = Rc::clone(iter.left_parent.as_ref().unwrap());
         }
         return ASTNode {
             sym: nt(state.lhs.clone()),
             children,
+            start: state.start_idx,
+            end,
         };
     }
 
-    mem[input.len()]
+    let tree = mem[input.len()]
         .iter()
-        .filter(|&s| s.lhs == grammar.start && s.start_idx == 0 && s.done())
-        .nth(0)
-        .map(|state| generate_parse_tree(Rc::clone(state)))
+        .find(|&s| s.lhs == grammar.start && s.start_idx == 0 && s.done())
+        .map(|state| generate_parse_tree(input, Rc::clone(state), input.len()));
+
+    tree.ok_or_else(|| {
+        // Every position up to the last non-empty chart entry was reachable,
+        // so that is where the parse died
+        let offset = (0..=input.len()).rev().find(|&i| !mem[i].is_empty()).unwrap_or(0);
+        let expected = mem[offset]
+            .iter()
+            .filter(|s| !s.done())
+            .filter_map(|s| match s.next_sym() {
+                Symbol::Terminal(t) | Symbol::Range(t, _) => Some(t),
+                Symbol::NonTerminal(_) => None,
+            })
+            .collect();
+        let context = mem[offset]
+            .iter()
+            .filter(|s| !s.done() && s.rhs_idx > 0)
+            .map(|s| s.lhs.clone())
+            .collect();
+        ParseError { offset, expected, context }
+    })
 }
 
 /// A struct with a pretty `Debug` impl for `ASTNode`s.
@@ -319,6 +391,7 @@ impl<'a> std::fmt::Debug for PrettyPrint<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.0.sym {
             Symbol::Terminal(c) => write!(f, "'{}'", c),
+            Symbol::Range(lo, hi) => write!(f, "'{}'-'{}'", lo, hi),
             Symbol::NonTerminal(ref s) => {
                 let mut tup = f.debug_tuple(s);
                 for child in self.0.children() {
@@ -0,0 +1,158 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use super::earley_parse::ParseError;
+
+// Tokens that continue or close a piece of a pattern, as opposed to a literal.
+// These are listed individually when describing what the parser expected;
+// everything else is summarised as "a literal character".
+const SYNTAX_TOKENS: [char; 6] = [')', '|', '*', '+', '?', '{'];
+const CLASS_SYNTAX_TOKENS: [char; 2] = [']', '-'];
+// Rules of the grammar that are partway through a bracket expression, and
+// rules that have just read a backslash and need the character it escapes
+const CLASS_RULES: [&str; 7] = ["CLASS", "NEGCLASS", "CBODY", "CRANGE", "CCHAR", "CSET", "CBADESC"];
+const ESCAPE_RULES: [&str; 2] = ["BADESC", "CBADESC"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    // The pattern stopped fitting the grammar, `expected` holds the characters
+    // that could have continued it and `context` the rules they would have
+    // continued
+    Syntax { expected: BTreeSet<char>, context: BTreeSet<String> },
+    // A bracket expression range whose start comes after its end
+    InvalidRange { start: char, end: char },
+    // A counted repetition that would grow the NFA past its size limit
+    RepeatTooLarge { limit: usize },
+    // A counted repetition whose minimum is larger than its maximum
+    InvalidRepeat { min: usize, max: usize },
+    // Two capture groups sharing a name
+    DuplicateGroupName { name: String },
+    // A \p{...} or \P{...} naming no general category or script
+    UnknownProperty { name: String },
+    // A \n outside multiline mode, where it could never match
+    NewlineNotAllowed,
+    // A backslash before a letter, digit or character that starts no escape
+    UnknownEscape { escape: char },
+    // A \x or \u escape whose number is no Unicode scalar value
+    InvalidCodePoint { escape: String },
+}
+
+// An invalid pattern and the byte offset into it where the problem was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexError {
+    pub kind: ErrorKind,
+    pub offset: usize,
+}
+
+impl RegexError {
+    pub fn new(kind: ErrorKind, offset: usize) -> Self {
+        RegexError { kind, offset }
+    }
+
+    // Renders the error with the pattern underneath and a caret under the
+    // offending character, for printing to a terminal
+    pub fn diagnostic(&self, pattern: &str) -> String {
+        let mut result = format!("error: {}\n", self.message(pattern));
+        result.push_str(&format!("  {}\n", pattern));
+        let column = pattern[..self.offset].chars().count();
+        result.push_str(&format!("  {}^", " ".repeat(column)));
+        if let ErrorKind::Syntax { expected, context } = &self.kind {
+            if !expected.is_empty() {
+                result.push_str(&format!("\n  expected {}", describe_expected(expected, context)));
+            }
+        }
+        return result;
+    }
+
+    fn message(&self, pattern: &str) -> String {
+        match &self.kind {
+            ErrorKind::Syntax { .. } => match pattern[self.offset..].chars().next() {
+                Some(c) => format!("unexpected '{}' at byte {}", c, self.offset),
+                None => format!("unexpected end of pattern at byte {}", self.offset),
+            },
+            ErrorKind::InvalidRange { start, end } => {
+                format!("invalid range {}-{} in bracket expression, {} comes after {}", start, end, start, end)
+            }
+            ErrorKind::RepeatTooLarge { limit } => {
+                format!("counted repetition is too large, the NFA is limited to {} states", limit)
+            }
+            ErrorKind::InvalidRepeat { min, max } => {
+                format!("invalid repetition {{{},{}}}, the minimum is larger than the maximum", min, max)
+            }
+            ErrorKind::DuplicateGroupName { name } => {
+                format!("duplicate capture group name '{}'", name)
+            }
+            ErrorKind::UnknownProperty { name } => {
+                format!("unknown Unicode property '{}'", name)
+            }
+            ErrorKind::NewlineNotAllowed => {
+                "a newline can only be matched in multiline mode, use -U or (?m)".to_string()
+            }
+            ErrorKind::UnknownEscape { escape } => {
+                format!("unknown escape sequence '\\{}'", escape)
+            }
+            ErrorKind::InvalidCodePoint { escape } => {
+                format!("invalid escape '{}', not a Unicode scalar value", escape)
+            }
+        }
+    }
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::Syntax { expected, context } if !expected.is_empty() => {
+                write!(f, "syntax error at byte {}, expected {}", self.offset, describe_expected(expected, context))
+            }
+            ErrorKind::Syntax { .. } => write!(f, "syntax error at byte {}", self.offset),
+            _ => write!(f, "{} at byte {}", self.message(""), self.offset),
+        }
+    }
+}
+
+impl std::error::Error for RegexError {}
+
+impl From<ParseError> for RegexError {
+    fn from(e: ParseError) -> Self {
+        RegexError::new(ErrorKind::Syntax { expected: e.expected, context: e.context }, e.offset)
+    }
+}
+
+// Lists the expected tokens, collapsing runs of interchangeable literals.
+// `context` holds the rules the tokens would have continued.
+fn describe_expected(expected: &BTreeSet<char>, context: &BTreeSet<String>) -> String {
+    // After a backslash nearly any character goes
+    if context.iter().any(|rule| ESCAPE_RULES.contains(&rule.as_str())) {
+        return "a character to escape".to_string();
+    }
+    let mut parts: Vec<String> = Vec::new();
+    // Inside \x and \u escapes only hex digits are literals
+    let hex = expected.contains(&'F') && !expected.contains(&'G');
+    let literal = !hex && expected.contains(&'a');
+    let digit = !literal && !hex && ('0'..='9').all(|d| expected.contains(&d));
+    let class = context.iter().any(|rule| CLASS_RULES.contains(&rule.as_str()));
+    let syntax: &[char] = if class { &CLASS_SYNTAX_TOKENS } else { &SYNTAX_TOKENS };
+    if literal {
+        parts.push("a literal character".to_string());
+    }
+    if digit {
+        parts.push("a digit".to_string());
+    }
+    if hex {
+        parts.push("a hex digit".to_string());
+    }
+    for c in expected.iter() {
+        if (literal && !syntax.contains(c)) || (digit && c.is_ascii_digit()) || (hex && c.is_ascii_hexdigit()) {
+            continue;
+        }
+        parts.push(format!("'{}'", c.escape_default()));
+    }
+
+    match parts.len() {
+        1 => return parts.pop().unwrap(),
+        _ => {
+            let last = parts.pop().unwrap();
+            return format!("{} or {}", parts.join(", "), last);
+        }
+    }
+}
@@ -2,7 +2,7 @@ use super::earley_parse::*;
 use std::collections::HashSet;
 
 pub fn our_grammar() -> CFG {
-    let special = HashSet::from([ '|', '*', '(', ')', '.', '+', '?', '\\']);
+    let special = HashSet::from([ '|', '*', '(', ')', '.', '+', '?', '\\', '[', '^', '$', '{', '}']);
     let mut g = CFG::new("RE");
 
     g.add_rule("RE", vec![nt("UNION")]);
@@ -12,14 +12,62 @@ pub fn our_grammar() -> CFG {
     g.add_rule("CONCAT", vec![nt("CONCAT"), nt("COUNTS")]);
     g.add_rule("CONCAT", vec![nt("COUNTS")]);
 
+    // Inline flags: (?i) turns on case insensitivity, (?m) multiline mode and
+    // (?s) lets '.' match a newline too, until the end of the enclosing group.
+    // Flags after a '-' are turned off, as in (?i-s) or (?-m). They match
+    // nothing, so they sit between pieces rather than being one that could be
+    // repeated.
+    g.add_rule("CONCAT", vec![nt("CONCAT"), nt("FLAGS")]);
+    g.add_rule("CONCAT", vec![nt("FLAGS")]);
+    g.add_rule("FLAGS", vec![tr('('), tr('?'), nt("FLAG"), tr(')')]);
+    g.add_rule("FLAG", vec![nt("FLAGLIST")]);
+    g.add_rule("FLAG", vec![nt("FLAGLIST"), tr('-'), nt("FLAGLIST")]);
+    g.add_rule("FLAG", vec![tr('-'), nt("FLAGLIST")]);
+    g.add_rule("FLAGLIST", vec![nt("FLAGLIST"), nt("FLAGCHAR")]);
+    g.add_rule("FLAGLIST", vec![nt("FLAGCHAR")]);
+    for c in ['i', 'm', 's'] {
+        g.add_rule("FLAGCHAR", vec![tr(c)]);
+    }
+
     g.add_rule("COUNTS", vec![nt("COUNTS"), tr('*')]);
     g.add_rule("COUNTS", vec![nt("COUNTS"), tr('+')]);
     g.add_rule("COUNTS", vec![nt("COUNTS"), tr('?')]);
+    g.add_rule("COUNTS", vec![nt("REPEAT")]);
     g.add_rule("COUNTS", vec![nt("PAREN")]);
 
+    // Counted repetition: x{n}, x{n,}, x{n,m}
+    g.add_rule("REPEAT", vec![nt("COUNTS"), tr('{'), nt("NUM"), tr('}')]);
+    g.add_rule("REPEAT", vec![nt("COUNTS"), tr('{'), nt("NUM"), tr(','), tr('}')]);
+    g.add_rule("REPEAT", vec![nt("COUNTS"), tr('{'), nt("NUM"), tr(','), nt("NUM"), tr('}')]);
+    g.add_rule("NUM", vec![nt("NUM"), nt("DIGIT")]);
+    g.add_rule("NUM", vec![nt("DIGIT")]);
+    for c in '0'..='9' {
+        g.add_rule("DIGIT", vec![tr(c)]);
+    }
+
     g.add_rule("PAREN", vec![tr('('), nt("RE"), tr(')')]);
     g.add_rule("PAREN", vec![nt("TERM")]);
 
+    // Groups: (x) captures, (?:x) only groups, (?P<name>x) and (?<name>x)
+    // capture under a name as well as a number
+    g.add_rule("PAREN", vec![nt("NCGROUP")]);
+    g.add_rule("PAREN", vec![nt("NAMED")]);
+    g.add_rule("NCGROUP", vec![tr('('), tr('?'), tr(':'), nt("RE"), tr(')')]);
+    // (?i:x), (?-i:x) and the like set the flags for x only
+    g.add_rule("PAREN", vec![nt("FLAGGROUP")]);
+    g.add_rule("FLAGGROUP", vec![tr('('), tr('?'), nt("FLAG"), tr(':'), nt("RE"), tr(')')]);
+    g.add_rule("NAMED", vec![tr('('), tr('?'), tr('P'), tr('<'), nt("NAME"), tr('>'), nt("RE"), tr(')')]);
+    g.add_rule("NAMED", vec![tr('('), tr('?'), tr('<'), nt("NAME"), tr('>'), nt("RE"), tr(')')]);
+    g.add_rule("NAME", vec![nt("NAME"), nt("NAMECHAR")]);
+    g.add_rule("NAME", vec![nt("NAMESTART")]);
+    g.add_rule("NAMECHAR", vec![nt("NAMESTART")]);
+    for c in ('a'..='z').chain('A'..='Z').chain(['_']) {
+        g.add_rule("NAMESTART", vec![tr(c)]);
+    }
+    for c in '0'..='9' {
+        g.add_rule("NAMECHAR", vec![tr(c)]);
+    }
+
     g.add_rule("TERM", vec![nt("LET")]);
     g.add_rule("TERM", vec![nt("SP")]);
     g.add_rule("TERM", vec![nt("DGT")]);
@@ -79,25 +127,141 @@ pub fn our_grammar() -> CFG {
         }
     }
 
+    // Anything outside ASCII is a literal
+    g.add_rule("SP", vec![tr_range('\u{80}', char::MAX)]);
+
     g.add_rule("TERM", vec![nt("SP")]);
-    for c in special.iter(){
-        g.add_rule("SP", vec![tr('\\'), tr(*c)]);
+    // A backslash makes any ASCII punctuation or space a literal, special or
+    // not
+    for c in escaped_literals() {
+        g.add_rule("SP", vec![tr('\\'), tr(c)]);
     }
 
+    // Characters written as escapes: \t, \n and the like, \xHH or \x{H...}
+    // and \uHHHH or \u{H...}. A letter or digit that starts no escape is an
+    // error rather than a literal, so every escape means the same here as in
+    // other engines.
+    g.add_rule("TERM", vec![nt("ESC")]);
+    g.add_rule("TERM", vec![nt("BADESC")]);
+    add_escapes(&mut g);
+    add_unknown_escapes(&mut g, "BADESC", &['s', 'd', 'w', 'S', 'D', 'W', 'p', 'P', 'A', 'z']);
+
     g.add_rule("TERM", vec![nt("DOT")]);
     g.add_rule("DOT", vec![tr('.')]);
 
-    g.add_rule("LET", vec![tr('\\'), tr('s')]);
-    g.add_rule("DGT", vec![tr('\\'), tr('d')]);
-    g.add_rule("WS", vec![tr('\\'), tr('w')]);
+    // Perl classes: \d for digits, \s for whitespace and \w for word
+    // characters, and their complements \D, \S and \W
+    g.add_rule("TERM", vec![nt("PERLCLASS")]);
+    for c in ['d', 's', 'w', 'D', 'S', 'W'] {
+        g.add_rule("PERLCLASS", vec![tr('\\'), tr(c)]);
+    }
+
+    // Unicode general categories and scripts: \p{L}, \p{Greek}, \pL, and
+    // their complements \P{...}
+    g.add_rule("TERM", vec![nt("PROP")]);
+    g.add_rule("TERM", vec![nt("NOTPROP")]);
+    g.add_rule("PROP", vec![tr('\\'), tr('p'), nt("PNAME")]);
+    g.add_rule("NOTPROP", vec![tr('\\'), tr('P'), nt("PNAME")]);
+    g.add_rule("PNAME", vec![tr('{'), nt("NAME"), tr('}')]);
+    g.add_rule("PNAME", vec![nt("NAMESTART")]);
+
+    // Zero-width anchors: ^ and $ match at line boundaries, \A and \z only at
+    // the very start and end of the input
+    g.add_rule("TERM", vec![nt("ANCHOR")]);
+    g.add_rule("ANCHOR", vec![tr('^')]);
+    g.add_rule("ANCHOR", vec![tr('$')]);
+    g.add_rule("ANCHOR", vec![tr('\\'), tr('A')]);
+    g.add_rule("ANCHOR", vec![tr('\\'), tr('z')]);
+
+    // Bracket expressions: [abc], [a-z0-9_], [^...]
+    // A leading '^' negates the class, so the first item may not be a bare '^'.
+    // A bare '-' is only allowed as the first or last character of the class.
+    g.add_rule("TERM", vec![nt("CLASS")]);
+    g.add_rule("TERM", vec![nt("NEGCLASS")]);
+    g.add_rule("CLASS", vec![tr('['), nt("CBODY"), tr(']')]);
+    g.add_rule("NEGCLASS", vec![tr('['), tr('^'), nt("CBODY"), tr(']')]);
 
-    g.add_rule("TERM", vec![nt("NOTLET")]);
-    g.add_rule("TERM", vec![nt("NOTDGT")]);
-    g.add_rule("TERM", vec![nt("NOTWS")]);
+    g.add_rule("CBODY", vec![nt("CITEMS")]);
+    g.add_rule("CBODY", vec![tr('-'), nt("CITEMS")]);
+    g.add_rule("CBODY", vec![nt("CITEMS"), tr('-')]);
+    g.add_rule("CBODY", vec![tr('-'), nt("CITEMS"), tr('-')]);
+    g.add_rule("CBODY", vec![tr('-')]);
 
-    g.add_rule("NOTLET", vec![tr('\\'), tr('S')]);
-    g.add_rule("NOTDGT", vec![tr('\\'), tr('D')]);
-    g.add_rule("NOTWS", vec![tr('\\'), tr('W')]);
+    g.add_rule("CITEMS", vec![nt("CITEMS"), nt("CITEM")]);
+    g.add_rule("CITEMS", vec![nt("CFIRST")]);
+
+    g.add_rule("CFIRST", vec![nt("CCHAR")]);
+    g.add_rule("CFIRST", vec![nt("CSET")]);
+    g.add_rule("CFIRST", vec![nt("CRANGE")]);
+    g.add_rule("CITEM", vec![nt("CANY")]);
+    g.add_rule("CITEM", vec![nt("CSET")]);
+    g.add_rule("CITEM", vec![nt("CRANGE")]);
+
+    g.add_rule("CRANGE", vec![nt("CCHAR"), tr('-'), nt("CANY")]);
+
+    g.add_rule("CANY", vec![nt("CCHAR")]);
+    g.add_rule("CANY", vec![tr('^')]);
+
+    let class_special = HashSet::from([']', '\\', '-', '^']);
+    g.add_rule("CCHAR", vec![tr(char::from_u32(0x09).unwrap())]);
+    for n in 0x20..0x80 {
+        let option = char::from_u32(n);
+        let c = option.unwrap();
+        if !class_special.contains(&c) {
+            g.add_rule("CCHAR", vec![tr(c)]);
+        }
+    }
+    g.add_rule("CCHAR", vec![tr_range('\u{80}', char::MAX)]);
+    for c in escaped_literals() {
+        g.add_rule("CCHAR", vec![tr('\\'), tr(c)]);
+    }
+    // Escapes can be range ends too, as in [\x00-\x1f]
+    g.add_rule("CCHAR", vec![nt("ESC")]);
+    g.add_rule("CFIRST", vec![nt("CBADESC")]);
+    g.add_rule("CITEM", vec![nt("CBADESC")]);
+    add_unknown_escapes(&mut g, "CBADESC", &['s', 'd', 'w', 'S', 'D', 'W', 'p', 'P']);
+
+    for c in ['s', 'd', 'w', 'S', 'D', 'W'] {
+        g.add_rule("CSET", vec![tr('\\'), tr(c)]);
+    }
+    g.add_rule("CSET", vec![tr('\\'), tr('p'), nt("PNAME")]);
+    g.add_rule("CSET", vec![tr('\\'), tr('P'), nt("PNAME")]);
 
     return g;
+}
+
+// Letters that start an escape for a single character
+const ESCAPE_LETTERS: [char; 9] = ['a', 'e', 'f', 'n', 'r', 't', 'v', 'x', 'u'];
+
+// The characters a backslash turns into a literal
+fn escaped_literals() -> impl Iterator<Item = char> {
+    return (' '..='~').filter(|c| !c.is_ascii_alphanumeric());
+}
+
+fn add_escapes(g: &mut CFG) {
+    for c in ['a', 'e', 'f', 'n', 'r', 't', 'v'] {
+        g.add_rule("ESC", vec![tr('\\'), tr(c)]);
+    }
+    for c in ['x', 'u'] {
+        g.add_rule("ESC", vec![tr('\\'), tr(c), tr('{'), nt("HEXNUM"), tr('}')]);
+    }
+    g.add_rule("ESC", vec![tr('\\'), tr('x'), nt("HEX"), nt("HEX")]);
+    g.add_rule("ESC", vec![tr('\\'), tr('u'), nt("HEX"), nt("HEX"), nt("HEX"), nt("HEX")]);
+    g.add_rule("HEXNUM", vec![nt("HEXNUM"), nt("HEX")]);
+    g.add_rule("HEXNUM", vec![nt("HEX")]);
+    for c in ('0'..='9').chain('a'..='f').chain('A'..='F') {
+        g.add_rule("HEX", vec![tr(c)]);
+    }
+}
+
+// Rules making `name` a backslash followed by any letter or digit that is
+// neither in `known` nor starts a character escape, or by a character
+// outside ASCII
+fn add_unknown_escapes(g: &mut CFG, name: &str, known: &[char]) {
+    for c in ('0'..='9').chain('a'..='z').chain('A'..='Z') {
+        if !known.contains(&c) && !ESCAPE_LETTERS.contains(&c) {
+            g.add_rule(name, vec![tr('\\'), tr(c)]);
+        }
+    }
+    g.add_rule(name, vec![tr('\\'), tr_range('\u{80}', char::MAX)]);
 }
\ No newline at end of file
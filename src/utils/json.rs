@@ -0,0 +1,434 @@
+use std::fmt;
+
+use super::regex::Regex;
+use super::search::{Decoded, LineMatch};
+
+// The events --json writes, one JSON object per line. Every event has a
+// "type" and the "path" of the input it is about, "(standard input)" for
+// stdin. Offsets are in bytes; `offset` is where the line starts in the
+// input, and `start` and `end` count from the start of the line. Text that is
+// not UTF-8 has each invalid sequence written as U+FFFD, but offsets still
+// count the bytes of the input.
+//
+//   {"type":"begin","path":P}
+//       before the first line printed from an input
+//   {"type":"match","path":P,"line_number":N,"offset":N,"line":S,
+//    "submatches":[{"start":N,"end":N,"text":S,"captures":[C,...]},...]}
+//       a selected line, with each match in it; lines selected by -v have
+//       no submatches. "captures" holds groups 1 and up, each null when it
+//       took no part in the match or {"name":S|null,"start":N,"end":N,"text":S}
+//   {"type":"context","path":P,"line_number":N,"offset":N,"line":S}
+//       a line printed for -A, -B or -C
+//   {"type":"end","path":P,"stats":{"matched_lines":N,"matches":N}}
+//       after the last line printed from an input
+//
+// Inputs with nothing to print have no events. The line does not include
+// its newline. With -U a match may run over several lines, which then make
+// up the line of a single match event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Begin { path: String },
+    Match { path: String, line_number: usize, offset: usize, line: String, submatches: Vec<Submatch> },
+    Context { path: String, line_number: usize, offset: usize, line: String },
+    End { path: String, stats: FileStats },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submatch {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+    pub captures: Vec<Option<Capture>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    pub name: Option<String>,
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+// What was printed from an input: the selected lines and the matches in them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileStats {
+    pub matched_lines: usize,
+    pub matches: usize,
+}
+
+impl Event {
+    // The event for a line from the searcher, with the groups of each match
+    // the searcher found, which it does for --json. `regex` only gives their
+    // names.
+    pub fn from_line(path: &str, found: &LineMatch, regex: Option<&Regex>) -> Event {
+        let decoded = Decoded::new(found.line);
+        let (path, line) = (path.to_string(), decoded.text.to_string());
+        if found.context {
+            return Event::Context { path, line_number: found.number, offset: found.start, line };
+        }
+        let text = |start: usize, end: usize| decoded.text[decoded.text_offset(start)..decoded.text_offset(end)].to_string();
+        let names: Vec<Option<&str>> = regex.map_or(Vec::new(), |r| r.capture_names().skip(1).collect());
+        let submatches = found.spans.iter().enumerate().map(|(i, (start, end))| {
+            let groups = found.captures.get(i).map_or(&[][..], |groups| groups.as_slice());
+            let captures = groups.iter().zip(names.iter()).map(|(group, name)| group.map(|(s, e)| Capture {
+                name: name.map(String::from),
+                start: s,
+                end: e,
+                text: text(s, e),
+            })).collect();
+            Submatch { start: *start, end: *end, text: text(*start, *end), captures }
+        }).collect();
+        return Event::Match { path, line_number: found.number, offset: found.start, line, submatches };
+    }
+
+    pub fn to_json(&self) -> Json {
+        let string = |s: &str| Json::String(s.to_string());
+        let number = |n: usize| Json::Number(n as f64);
+        let object = |fields: Vec<(&str, Json)>| Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+        match self {
+            Event::Begin { path } => object(vec![("type", string("begin")), ("path", string(path))]),
+            Event::Match { path, line_number, offset, line, submatches } => {
+                let submatches = submatches.iter().map(|m| {
+                    let captures = m.captures.iter().map(|c| match c {
+                        Some(c) => object(vec![
+                            ("name", c.name.as_deref().map_or(Json::Null, string)),
+                            ("start", number(c.start)),
+                            ("end", number(c.end)),
+                            ("text", string(&c.text)),
+                        ]),
+                        None => Json::Null,
+                    }).collect();
+                    object(vec![("start", number(m.start)), ("end", number(m.end)), ("text", string(&m.text)), ("captures", Json::Array(captures))])
+                }).collect();
+                object(vec![
+                    ("type", string("match")),
+                    ("path", string(path)),
+                    ("line_number", number(*line_number)),
+                    ("offset", number(*offset)),
+                    ("line", string(line)),
+                    ("submatches", Json::Array(submatches)),
+                ])
+            }
+            Event::Context { path, line_number, offset, line } => object(vec![
+                ("type", string("context")),
+                ("path", string(path)),
+                ("line_number", number(*line_number)),
+                ("offset", number(*offset)),
+                ("line", string(line)),
+            ]),
+            Event::End { path, stats } => object(vec![
+                ("type", string("end")),
+                ("path", string(path)),
+                ("stats", object(vec![("matched_lines", number(stats.matched_lines)), ("matches", number(stats.matches))])),
+            ]),
+        }
+    }
+
+    // Reads back an event written by `to_json`, None if `json` is not one
+    pub fn from_json(json: &Json) -> Option<Event> {
+        let path = json.get("path")?.as_str()?.to_string();
+        let line = || Some(json.get("line")?.as_str()?.to_string());
+        let line_number = || json.get("line_number")?.as_usize();
+        let offset = || json.get("offset")?.as_usize();
+        let event = match json.get("type")?.as_str()? {
+            "begin" => Event::Begin { path },
+            "match" => {
+                let mut submatches = Vec::new();
+                for m in json.get("submatches")?.as_array()? {
+                    let mut captures = Vec::new();
+                    for c in m.get("captures")?.as_array()? {
+                        captures.push(match c {
+                            Json::Null => None,
+                            _ => Some(Capture {
+                                name: match c.get("name")? {
+                                    Json::Null => None,
+                                    name => Some(name.as_str()?.to_string()),
+                                },
+                                start: c.get("start")?.as_usize()?,
+                                end: c.get("end")?.as_usize()?,
+                                text: c.get("text")?.as_str()?.to_string(),
+                            }),
+                        });
+                    }
+                    submatches.push(Submatch {
+                        start: m.get("start")?.as_usize()?,
+                        end: m.get("end")?.as_usize()?,
+                        text: m.get("text")?.as_str()?.to_string(),
+                        captures,
+                    });
+                }
+                Event::Match { path, line_number: line_number()?, offset: offset()?, line: line()?, submatches }
+            }
+            "context" => Event::Context { path, line_number: line_number()?, offset: offset()?, line: line()? },
+            "end" => {
+                let stats = json.get("stats")?;
+                let stats = FileStats { matched_lines: stats.get("matched_lines")?.as_usize()?, matches: stats.get("matches")?.as_usize()? };
+                Event::End { path, stats }
+            }
+            _ => return None,
+        };
+        return Some(event);
+    }
+}
+
+// A JSON value. Objects keep their members in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    // Parses one JSON value, which may be surrounded by whitespace. The
+    // error says what was expected and at which byte.
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { text, pos: 0 };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.pos != text.len() {
+            return Err(parser.error("the end of the input"));
+        }
+        return Ok(value);
+    }
+
+    // The member `key` of an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+// Compact JSON, on one line
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 || c == '\u{7f}' => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'t> {
+    text: &'t str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, expected: &str) -> String {
+        format!("expected {} at byte {}", expected, self.pos)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(c) = self.peek().filter(|c| matches!(c, ' ' | '\t' | '\n' | '\r')) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.text[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            return true;
+        }
+        return false;
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('-' | '0'..='9') => self.number(),
+            _ if self.eat("null") => Ok(Json::Null),
+            _ if self.eat("true") => Ok(Json::Bool(true)),
+            _ if self.eat("false") => Ok(Json::Bool(false)),
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.eat("{");
+        let mut members = Vec::new();
+        self.whitespace();
+        if self.eat("}") {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("a string"));
+            }
+            let key = self.string()?;
+            self.whitespace();
+            if !self.eat(":") {
+                return Err(self.error("':'"));
+            }
+            members.push((key, self.value()?));
+            self.whitespace();
+            if self.eat("}") {
+                return Ok(Json::Object(members));
+            }
+            if !self.eat(",") {
+                return Err(self.error("',' or '}'"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.eat("[");
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.eat("]") {
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            if self.eat("]") {
+                return Ok(Json::Array(items));
+            }
+            if !self.eat(",") {
+                return Err(self.error("',' or ']'"));
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        while let Some(c) = self.peek().filter(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+            self.pos += c.len_utf8();
+        }
+        return match self.text[start..self.pos].parse::<f64>() {
+            Ok(n) => Ok(Json::Number(n)),
+            Err(_) => {
+                self.pos = start;
+                Err(self.error("a number"))
+            }
+        };
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.pos..self.pos + 4).filter(|d| d.chars().all(|c| c.is_ascii_hexdigit()));
+        let code = match digits {
+            Some(digits) => u32::from_str_radix(digits, 16).unwrap(),
+            None => return Err(self.error("four hex digits")),
+        };
+        self.pos += 4;
+        return Ok(code);
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.eat("\"");
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(self.error("'\"'")),
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = self.peek().ok_or_else(|| self.error("an escape"))?;
+                    self.pos += escape.len_utf8();
+                    match escape {
+                        '"' | '\\' | '/' => s.push(escape),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'u' => {
+                            let mut code = self.hex4()?;
+                            // A surrogate pair stands for one character
+                            if (0xd800..0xdc00).contains(&code) && self.eat("\\u") {
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            s.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        _ => {
+                            self.pos -= escape.len_utf8();
+                            return Err(self.error("an escape"));
+                        }
+                    }
+                }
+                c if (c as u32) < 0x20 => {
+                    self.pos -= 1;
+                    return Err(self.error("a character that is not a control character"));
+                }
+                c => s.push(c),
+            }
+        }
+    }
+}
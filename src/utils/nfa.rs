@@ -1,34 +1,152 @@
-use std::{collections::HashSet};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use super::earley_parse::*;
 use super::state_utils::*;
+use super::error::*;
+use super::unicode::*;
 
-pub fn construct_nfa(curr: &ASTNode, states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
+// Options and limits applied while building the NFA and the automata
+// derived from it
+#[derive(Debug, Clone)]
+pub struct NfaConfig {
+    // Match letters regardless of case, as if the pattern began with (?i)
+    pub case_insensitive: bool,
+    // Let the pattern match newlines, as if it began with (?m)
+    pub multiline: bool,
+    // Give \s and \w their old meanings, letters and tab or space
+    pub posix_classes: bool,
+    // Most states the NFA may hold once counted repetitions are expanded
+    pub size_limit: usize,
+    // Most states a full DFA may have, past it a lazy DFA is used instead
+    pub dfa_state_limit: usize,
+    // Bytes the lazy DFA may cache before flushing
+    pub dfa_cache_limit: usize,
+}
+
+impl Default for NfaConfig {
+    fn default() -> Self {
+        NfaConfig { case_insensitive: false, multiline: false, posix_classes: false, size_limit: 5000, dfa_state_limit: 2000, dfa_cache_limit: 2 << 20 }
+    }
+}
+
+// What construct_nfa needs to know besides the node it is building
+#[derive(Debug, Clone)]
+pub struct NfaContext<'a> {
+    pub config: &'a NfaConfig,
+    // Byte offset of each capture group's '(' in the pattern, group 1 first.
+    // Groups are numbered by offset so every copy made by a counted repetition
+    // records into the same group.
+    pub groups: Vec<usize>,
+    // The inline flags in effect at the node being built. Nodes are built in
+    // pattern order and groups restore them on the way out, so a flag lasts
+    // to the end of the group it appears in.
+    pub flags: Cell<Flags>,
+}
+
+// The flags that (?i), (?m) and (?s) turn on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub case_insensitive: bool,
+    pub multiline: bool,
+    pub dot_all: bool,
+}
+
+impl Flags {
+    // Whether the pattern may match a newline here. Complemented sets such as
+    // [^a] or \S include one only then, and '.' only with (?s) as well.
+    fn newline(&self) -> bool {
+        return self.multiline || self.dot_all;
+    }
+}
+
+impl NfaContext<'_> {
+    fn group_index(&self, curr: &ASTNode) -> usize {
+        return self.groups.iter().position(|&start| start == curr.start).unwrap() + 1;
+    }
+
+    fn fold(&self) -> bool {
+        return self.flags.get().case_insensitive;
+    }
+
+    fn newline(&self) -> bool {
+        return self.flags.get().newline();
+    }
+
+    // The characters of \d, \s or \w, or complemented of \D, \S or \W
+    fn perl_class(&self, c: char) -> Vec<CharRange> {
+        let posix = self.config.posix_classes;
+        let ranges = match c.to_ascii_lowercase() {
+            's' if posix => let_ranges(),
+            'w' if posix => ws_ranges(),
+            's' => space_ranges(),
+            'w' => word_ranges(),
+            _ => dgt_ranges(),
+        };
+        if c.is_ascii_uppercase() {
+            return complement_ranges(ranges, self.newline());
+        }
+        if !self.newline() {
+            // Drop the newline \s holds
+            return negate_ranges(negate_ranges(ranges));
+        }
+        return ranges;
+    }
+}
+
+pub fn construct_nfa(curr: &ASTNode, states: &mut Vec<State>, frag_stack: &mut Vec<usize>, ctx: &NfaContext) -> Result<(), RegexError> {
     match curr.sym {
         Symbol::Terminal(c) => {
             if c == '.' {
-                create_dot_state(states, frag_stack);
+                create_dot_state(ctx.flags.get().dot_all, states, frag_stack);
+            }
+            else if c == '^' {
+                assertion_state(Assertion::LineStart, states, frag_stack);
+            }
+            else if c == '$' {
+                assertion_state(Assertion::LineEnd, states, frag_stack);
             }
             else {
-                terminal_state_from_char(c, states, frag_stack);
+                terminal_state_from_char(c, ctx.fold(), states, frag_stack);
             }
         }
         Symbol::NonTerminal(ref s) => {
             match s.as_str() {
-                "PAREN" => {
-                    construct_nfa(&curr.children[1], states, frag_stack);
+                "PAREN" | "NAMED" => {
+                    let flags = ctx.flags.get();
+                    construct_nfa(&curr.children[curr.children.len() - 2], states, frag_stack, ctx)?;
+                    ctx.flags.set(flags);
+                    let e = frag_stack.pop().unwrap();
+                    frag_stack.push(capture_states(ctx.group_index(curr), e, states));
+                }
+                "NCGROUP" => {
+                    let flags = ctx.flags.get();
+                    construct_nfa(&curr.children[3], states, frag_stack, ctx)?;
+                    ctx.flags.set(flags);
+                }
+                "FLAGS" => {
+                    // Zero-width, the flags apply to what follows
+                    ctx.flags.set(set_flags(&curr.children[2], ctx.flags.get()));
+                    frag_stack.push(states.len());
+                    states.push(split_state(vec![None]));
+                }
+                "FLAGGROUP" => {
+                    let flags = ctx.flags.get();
+                    ctx.flags.set(set_flags(&curr.children[2], flags));
+                    construct_nfa(&curr.children[4], states, frag_stack, ctx)?;
+                    ctx.flags.set(flags);
                 }
                 "CONCAT" => {
-                    construct_nfa(&curr.children[0], states, frag_stack);
-                    construct_nfa(&curr.children[1], states, frag_stack);
+                    construct_nfa(&curr.children[0], states, frag_stack, ctx)?;
+                    construct_nfa(&curr.children[1], states, frag_stack, ctx)?;
                     let e2 = frag_stack.pop().unwrap();
                     let e1 = frag_stack.pop().unwrap();
                     patch(e1, e2, states);
                     frag_stack.push(e1);
                 }
                 "UNION" => {
-                    construct_nfa(&curr.children[0], states, frag_stack);
-                    construct_nfa(&curr.children[2], states, frag_stack);
+                    construct_nfa(&curr.children[0], states, frag_stack, ctx)?;
+                    construct_nfa(&curr.children[2], states, frag_stack, ctx)?;
                     let e2 = frag_stack.pop().unwrap();
                     let e1 = frag_stack.pop().unwrap();
                     let new_state = split_state(vec![Some(e1), Some(e2)]);
@@ -36,92 +154,284 @@ pub fn construct_nfa(curr: &ASTNode, states: &mut Vec<State>, frag_stack: &mut V
                     states.push(new_state);
                 }
                 "COUNTS" => {
-                    construct_nfa(&curr.children[0], states, frag_stack);
+                    construct_nfa(&curr.children[0], states, frag_stack, ctx)?;
                     let e = frag_stack.pop().unwrap();
-                    let mut new_state = split_state(vec![Some(e)]);
-                    let new_state_location = states.len();
-                    match curr.children[1].sym {
-                        Symbol::Terminal('*') => {
-                            patch(e, new_state_location, states);
-                            frag_stack.push(new_state_location);
-                        }
-                        Symbol::Terminal('?') => {
-                            frag_stack.push(new_state_location);
-                        }
-                        Symbol::Terminal('+') => { 
-                            patch(e, new_state_location, states);
-                            frag_stack.push(e);
-                        }
-                        _ => {}
+                    if let Symbol::Terminal(op) = curr.children[1].sym {
+                        frag_stack.push(counted_fragment(e, op, states));
                     }
-                    new_state.out.push(Transition{c: None, state: None});
-                    states.push(new_state)
                 }
-                "LET" => {
-                    let_states(states, frag_stack);
+                "REPEAT" => {
+                    repeat_fragment(curr, states, frag_stack, ctx)?;
                 }
-                "DGT" => {
-                    dgt_states(states, frag_stack);
+                "PERLCLASS" => {
+                    if let Symbol::Terminal(c) = curr.children[1].sym {
+                        states_from_ranges(ctx.perl_class(c), states, frag_stack);
+                    }
                 }
-                "WS" => {
-                    ws_states(states, frag_stack);
+                "PROP" | "NOTPROP" => {
+                    states_from_ranges(property_set(curr, ctx)?, states, frag_stack);
                 }
-                "NOTLET" => {
-                    dgt_states(states, frag_stack);
-                    ws_states(states, frag_stack);
-                    sp_states(states, frag_stack);
-
-                    let mut states_vec: Vec<State> = Vec::new();
-                    for _ in 0..3 {
-                        states_vec.push(states[frag_stack.pop().unwrap()].clone());
+                "CLASS" | "NEGCLASS" => {
+                    let negated = s == "NEGCLASS";
+                    let first = if negated { 2 } else { 1 };
+                    let mut ranges: Vec<CharRange> = Vec::new();
+                    for child in &curr.children[first..curr.children.len() - 1] {
+                        class_ranges(child, ctx, &mut ranges)?;
                     }
-                    let new_state = merge_non_match_states(states_vec);
-                    frag_stack.push(states.len());
-                    states.push(new_state);
+                    class_states(ranges, negated, ctx.fold(), ctx.newline(), states, frag_stack);
                 }
-                "NOTDGT" => {
-                    let_states(states, frag_stack);
-                    ws_states(states,  frag_stack);
-                    sp_states(states, frag_stack);
-
-                    let mut states_vec: Vec<State> = Vec::new();
-                    for _ in 0..3 {
-                        states_vec.push(states[frag_stack.pop().unwrap()].clone());
+                "SP" => {
+                    // Escaped metacharacter, always a literal
+                    if let Symbol::Terminal(c) = curr.children[1].sym {
+                        terminal_state_from_char(c, ctx.fold(), states, frag_stack);
                     }
-                    let new_state = merge_non_match_states(states_vec);
-                    frag_stack.push(states.len());
-                    states.push(new_state);
                 }
-                "NOTWS" => {
-                    let_states(states, frag_stack);
-                    dgt_states(states, frag_stack);
-                    sp_states(states, frag_stack);
+                "ESC" => {
+                    terminal_state_from_char(escape_char(curr, ctx)?, ctx.fold(), states, frag_stack);
+                }
+                "BADESC" => {
+                    return Err(unknown_escape(curr));
+                }
+                "ANCHOR" => {
+                    match curr.children[1].sym {
+                        Symbol::Terminal('A') => assertion_state(Assertion::TextStart, states, frag_stack),
+                        Symbol::Terminal('z') => assertion_state(Assertion::TextEnd, states, frag_stack),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Ranges only appear in the grammar, the tree holds what they matched
+        Symbol::Range(_, _) => {}
+    }
+    return Ok(());
+}
+
+// Wraps the fragment starting at `e` in a '*', '+' or '?' loop and returns the
+// entry of the new fragment
+fn counted_fragment(e: usize, op: char, states: &mut Vec<State>) -> usize {
+    let mut new_state = split_state(vec![Some(e)]);
+    let new_state_location = states.len();
+    let entry = match op {
+        '*' => {
+            patch(e, new_state_location, states);
+            new_state_location
+        }
+        '+' => {
+            patch(e, new_state_location, states);
+            e
+        }
+        _ => new_state_location,
+    };
+    new_state.out.push(Transition{range: None, state: None, assertion: None, tag: None});
+    states.push(new_state);
+    return entry;
+}
+
+// Reads the bounds of `x{n}`, `x{n,}` or `x{n,m}`, None standing for no
+// maximum. A count too large to read could never fit in `size_limit` states.
+fn repeat_bounds(curr: &ASTNode, size_limit: usize) -> Result<(usize, Option<usize>), RegexError> {
+    let offset = curr.children[1].start;
+    let too_large = RegexError::new(ErrorKind::RepeatTooLarge { limit: size_limit }, offset);
+    let min = ast_number(&curr.children[2]).ok_or(too_large.clone())?;
+    let max = match curr.children.len() {
+        4 => Some(min),
+        5 => None,
+        _ => Some(ast_number(&curr.children[4]).ok_or(too_large)?),
+    };
+    if let Some(max) = max {
+        if max < min {
+            return Err(RegexError::new(ErrorKind::InvalidRepeat { min, max }, offset));
+        }
+    }
+    return Ok((min, max));
+}
+
+// Reads the decimal number spelled out by a NUM node, None if it overflows
+fn ast_number(curr: &ASTNode) -> Option<usize> {
+    match curr.sym {
+        Symbol::Terminal(c) => c.to_digit(10).map(|d| d as usize),
+        _ => {
+            let high = ast_number(&curr.children[0])?;
+            let low = ast_number(&curr.children[1])?;
+            high.checked_mul(10)?.checked_add(low)
+        }
+    }
+}
+
+// Expands a counted repetition by building one copy of the fragment per
+// repetition: the first `min` copies are required, the rest are optional, and
+// with no maximum the last copy loops
+fn repeat_fragment(curr: &ASTNode, states: &mut Vec<State>, frag_stack: &mut Vec<usize>, ctx: &NfaContext) -> Result<(), RegexError> {
+    let config = ctx.config;
+    let (min, max) = repeat_bounds(curr, config.size_limit)?;
+    let repeated = &curr.children[0];
+    if max == Some(0) {
+        frag_stack.push(states.len());
+        states.push(split_state(vec![None]));
+        return Ok(());
+    }
 
-                    let mut states_vec: Vec<State> = Vec::new();
-                    for _ in 0..3 {
-                        states_vec.push(states[frag_stack.pop().unwrap()].clone());
+    // Build one copy first to learn what each copy costs before committing
+    // to the rest
+    let before = states.len();
+    construct_nfa(repeated, states, frag_stack, ctx)?;
+    let copy_size = states.len() - before;
+    let copies = max.unwrap_or(min).max(1);
+    if copies.saturating_mul(copy_size + 1).saturating_add(before) > config.size_limit {
+        let kind = ErrorKind::RepeatTooLarge { limit: config.size_limit };
+        return Err(RegexError::new(kind, curr.children[1].start));
+    }
+
+    let mut entries: Vec<usize> = Vec::new();
+    for i in 0..copies {
+        if i > 0 {
+            construct_nfa(repeated, states, frag_stack, ctx)?;
+        }
+        let e = frag_stack.pop().unwrap();
+        let last = i + 1 == copies;
+        let entry = match max {
+            None if last && min == 0 => counted_fragment(e, '*', states),
+            None if last => counted_fragment(e, '+', states),
+            Some(_) if i >= min => counted_fragment(e, '?', states),
+            _ => e,
+        };
+        entries.push(entry);
+    }
+    for i in 1..entries.len() {
+        patch(entries[i - 1], entries[i], states);
+    }
+    frag_stack.push(entries[0]);
+    return Ok(());
+}
+
+// Collects the characters described by one piece of a bracket expression
+fn class_ranges(curr: &ASTNode, ctx: &NfaContext, ranges: &mut Vec<CharRange>) -> Result<(), RegexError> {
+    match curr.sym {
+        Symbol::Terminal(c) => {
+            ranges.push((c, c));
+        }
+        Symbol::NonTerminal(ref s) => {
+            match s.as_str() {
+                "CBODY" | "CITEMS" => {
+                    for child in curr.children.iter() {
+                        class_ranges(child, ctx, ranges)?;
                     }
-                    let new_state = merge_non_match_states(states_vec);
-                    frag_stack.push(states.len());
-                    states.push(new_state);
                 }
-                "SP" => {
-                    match &curr.children[1].sym {
-                        Symbol::Terminal(c) => {
-                            if *c == '.' {
-                                terminal_state_from_char(*c, states, frag_stack);
-                            }
-                            else {
-                                construct_nfa(&curr.children[1], states, frag_stack);
-                            }
-                        }
-                        Symbol::NonTerminal(_) => {}
+                "CCHAR" => {
+                    class_ranges(&curr.children[1], ctx, ranges)?;
+                }
+                "ESC" => {
+                    let c = escape_char(curr, ctx)?;
+                    ranges.push((c, c));
+                }
+                "CBADESC" => {
+                    return Err(unknown_escape(curr));
+                }
+                "CRANGE" => {
+                    let lo = class_range_end(&curr.children[0], ctx)?;
+                    let hi = class_range_end(&curr.children[2], ctx)?;
+                    if lo > hi {
+                        let kind = ErrorKind::InvalidRange { start: lo, end: hi };
+                        return Err(RegexError::new(kind, curr.start));
                     }
+                    ranges.push((lo, hi));
+                }
+                "CSET" => {
+                    let set = match curr.children[1].sym {
+                        Symbol::Terminal('p') | Symbol::Terminal('P') => property_set(curr, ctx)?,
+                        Symbol::Terminal(c) => ctx.perl_class(c),
+                        _ => Vec::new(),
+                    };
+                    ranges.extend(set);
                 }
                 _ => {}
             }
         }
+        // Ranges only appear in the grammar, the tree holds what they matched
+        Symbol::Range(_, _) => {}
+    }
+    return Ok(());
+}
+
+// The characters of a `\p{name}` or, complemented, a `\P{name}`. Case
+// variants are added before complementing.
+fn property_set(curr: &ASTNode, ctx: &NfaContext) -> Result<Vec<CharRange>, RegexError> {
+    let pname = &curr.children[2];
+    let name = ast_text(pname);
+    let name = name.trim_start_matches('{').trim_end_matches('}');
+    let ranges = match property_ranges(name) {
+        Some(ranges) => ranges,
+        None => {
+            let offset = if pname.is_terminal() { pname.start } else { pname.start + 1 };
+            let kind = ErrorKind::UnknownProperty { name: name.to_string() };
+            return Err(RegexError::new(kind, offset));
+        }
+    };
+    let ranges = if ctx.fold() { case_fold_ranges(ranges) } else { ranges };
+    if curr.children[1].sym == Symbol::Terminal('P') {
+        return Ok(complement_ranges(ranges, ctx.newline()));
+    }
+    return Ok(ranges);
+}
+
+// The flags in effect after a flag list such as `i`, `-i` or `im-s`, which
+// turns on the letters before a '-' and off the ones after it
+fn set_flags(curr: &ASTNode, mut flags: Flags) -> Flags {
+    let mut on = true;
+    for c in ast_text(curr).chars() {
+        match c {
+            '-' => on = false,
+            'i' => flags.case_insensitive = on,
+            'm' => flags.multiline = on,
+            's' => flags.dot_all = on,
+            _ => {}
+        }
+    }
+    return flags;
+}
+
+// The character at either end of a range: a plain or an escaped `CCHAR`, or
+// an `ESC`
+fn class_range_end(curr: &ASTNode, ctx: &NfaContext) -> Result<char, RegexError> {
+    match curr.sym {
+        Symbol::Terminal(c) => Ok(c),
+        Symbol::NonTerminal(ref s) if s == "ESC" => escape_char(curr, ctx),
+        _ => class_range_end(&curr.children[1], ctx),
+    }
+}
+
+// The character an `ESC` such as `\t`, `\x41` or `\u{1F600}` stands for. A
+// newline is only allowed where the pattern may match one.
+fn escape_char(curr: &ASTNode, ctx: &NfaContext) -> Result<char, RegexError> {
+    let text = ast_text(curr);
+    let c = match text.as_bytes()[1] {
+        b'a' => '\x07',
+        b'e' => '\x1b',
+        b'f' => '\x0c',
+        b'n' => '\n',
+        b'r' => '\r',
+        b't' => '\t',
+        b'v' => '\x0b',
+        _ => {
+            let digits = text[2..].trim_start_matches('{').trim_end_matches('}');
+            let code = u32::from_str_radix(digits, 16).ok().and_then(char::from_u32);
+            match code {
+                Some(c) => c,
+                None => return Err(RegexError::new(ErrorKind::InvalidCodePoint { escape: text }, curr.start)),
+            }
+        }
+    };
+    if c == '\n' && !ctx.newline() {
+        return Err(RegexError::new(ErrorKind::NewlineNotAllowed, curr.start));
     }
+    return Ok(c);
+}
+
+fn unknown_escape(curr: &ASTNode) -> RegexError {
+    let escape = ast_text(&curr.children[1]).chars().next().unwrap();
+    return RegexError::new(ErrorKind::UnknownEscape { escape }, curr.start);
 }
 
 pub fn ep_expansion(states: &mut Vec<State>) {
@@ -138,10 +448,10 @@ pub fn ep_expansion(states: &mut Vec<State>) {
             for j in 0..curr_out_len {
                 let curr_transition = &curr_state.out[j];
 
-                if curr_transition.c == None {
+                if curr_transition.is_epsilon() {
                     let next_state = &states_clone[curr_transition.state.unwrap()];
                     for next_transition in next_state.out.iter() {
-                        if next_transition.c == None {
+                        if next_transition.is_epsilon() {
                             let temp_set: HashSet<Transition> = curr_state.out.iter().cloned().collect();
                             if !temp_set.contains(next_transition) {
                                 curr_state.out.push(next_transition.clone());
@@ -164,12 +474,12 @@ pub fn ep_match_fix(states: &mut Vec<State>) {
         let state_len = states.len();
         let states_clone = states.clone();
         for i in 0..state_len {
-            let mut curr_state = &mut states[i];
+            let curr_state = &mut states[i];
             let curr_out_len = curr_state.out.len();
             for j in 0..curr_out_len {
                 let curr_transition = &curr_state.out[j];
 
-                if curr_transition.c == None {
+                if curr_transition.is_epsilon() {
                     let next_state = &states_clone[curr_transition.state.unwrap()];
                     if next_state.is_match && !curr_state.is_match {
                         curr_state.is_match = true;
@@ -196,7 +506,7 @@ pub fn ep_removal(states: &mut Vec<State>) {
             for j in 0..curr_out_len {
                 let curr_transition = &curr_state.out[j];
 
-                if curr_transition.c == None {
+                if curr_transition.is_epsilon() {
                     to_remove.push(j);
                     let next_state = &states_clone[curr_transition.state.unwrap()];
                     for next_transition in next_state.out.iter() {
@@ -208,25 +518,100 @@ pub fn ep_removal(states: &mut Vec<State>) {
                     }
                 }
             }
-            let mut offset = 0;
-            for j in to_remove.iter() {
+            for (offset, j) in to_remove.iter().enumerate() {
                 curr_state.out.remove(j - offset);
-                offset += 1;
             }
         }
-    }    
+    }
+    for state in states.iter_mut() {
+        merge_ranges(&mut state.out);
+    }
+}
+
+// Merges the ranges of character transitions to the same state where they
+// overlap or touch, so a state has at most one transition per run of
+// characters leading to each next state
+fn merge_ranges(out: &mut Vec<Transition>) {
+    let mut by_state: Vec<(Option<usize>, Vec<CharRange>)> = Vec::new();
+    let mut rest: Vec<Transition> = Vec::new();
+    for transition in out.drain(..) {
+        let range = match transition.range {
+            Some(range) => range,
+            None => {
+                rest.push(transition);
+                continue;
+            }
+        };
+        let next = transition.state;
+        match by_state.iter_mut().find(|(state, _)| *state == next) {
+            Some((_, ranges)) => ranges.push(range),
+            None => by_state.push((next, vec![range])),
+        }
+    }
+    for (next, ranges) in by_state {
+        for range in normalize_ranges(ranges) {
+            out.push(Transition {range: Some(range), state: next, assertion: None, tag: None});
+        }
+    }
+    out.extend(rest);
+}
+
+// Collects the capture groups in the order their '(' appears in the pattern,
+// along with the names of the named ones
+fn collect_groups(curr: &ASTNode, groups: &mut Vec<(usize, Option<String>)>) -> Result<(), RegexError> {
+    if let Symbol::NonTerminal(ref s) = curr.sym {
+        match s.as_str() {
+            "PAREN" => groups.push((curr.start, None)),
+            "NAMED" => {
+                let name_node = &curr.children[curr.children.len() - 4];
+                let name = ast_text(name_node);
+                if groups.iter().any(|(_, n)| n.as_deref() == Some(name.as_str())) {
+                    let kind = ErrorKind::DuplicateGroupName { name };
+                    return Err(RegexError::new(kind, name_node.start));
+                }
+                groups.push((curr.start, Some(name)));
+            }
+            _ => {}
+        }
+    }
+    for child in curr.children.iter() {
+        collect_groups(child, groups)?;
+    }
+    return Ok(());
+}
+
+// The characters of the pattern a node was parsed from
+fn ast_text(curr: &ASTNode) -> String {
+    match curr.sym {
+        Symbol::Terminal(c) => c.to_string(),
+        _ => curr.children.iter().map(ast_text).collect(),
+    }
 }
 
 // TODO Made ASTNode and strval for symbols public, is that ok? Not sure what else to do
 // except matching which seems excessive
 //getters? setters? sadge sadge
-pub fn cfg2nfa(grammar: ASTNode) -> (usize, Vec<State>) {
+// Name of each capture group, None for group 0 and for groups without one
+pub type GroupNames = Vec<Option<String>>;
+
+// Also returns the names of the capture groups
+pub fn cfg2nfa(grammar: ASTNode, config: &NfaConfig) -> Result<(usize, Vec<State>, GroupNames), RegexError> {
     let mut states: Vec<State> = Vec::new();
     let mut frag_stack: Vec<usize> = Vec::new();
 
     let grammar = grammar.collapse();
 
-    construct_nfa(&grammar, &mut states, &mut frag_stack);
+    let mut groups: Vec<(usize, Option<String>)> = Vec::new();
+    collect_groups(&grammar, &mut groups)?;
+    let mut names: GroupNames = vec![None];
+    names.extend(groups.iter().map(|(_, name)| name.clone()));
+    let ctx = NfaContext {
+        config,
+        groups: groups.into_iter().map(|(start, _)| start).collect(),
+        flags: Cell::new(Flags { case_insensitive: config.case_insensitive, multiline: config.multiline, dot_all: false }),
+    };
+
+    construct_nfa(&grammar, &mut states, &mut frag_stack, &ctx)?;
 
     let match_state = match_state();
     states.push(match_state);
@@ -234,48 +619,100 @@ pub fn cfg2nfa(grammar: ASTNode) -> (usize, Vec<State>) {
     ep_expansion(&mut states);
     ep_match_fix(&mut states);
     ep_removal(&mut states);
-    return (frag_stack.pop().unwrap(), states);
+    return Ok((frag_stack.pop().unwrap(), states, names));
 }
 
-pub fn prefix_extraction(start: usize, states: &mut Vec<State>) -> (usize, String, Vec<State>) {
-    let mut next_states: HashSet<Transition> = HashSet::new();
-    let mut curr_states: HashSet<Transition> = HashSet::new();
+// Number of states reachable from `start`, epsilon removal leaves the rest
+// of the vector unused
+pub fn reachable_states(start: usize, states: &[State]) -> usize {
+    let mut seen: HashSet<usize> = HashSet::from([start]);
+    let mut stack: Vec<usize> = vec![start];
+    while let Some(state) = stack.pop() {
+        for transition in states[state].out.iter() {
+            let next = transition.state.unwrap();
+            if seen.insert(next) {
+                stack.push(next);
+            }
+        }
+    }
+    return seen.len();
+}
+
+// The prefix is lowercase and the flag set when it has to be searched for
+// without regard to case, which is when its states match ASCII letters in
+// either case. The prefix stops at a letter whose case handling differs from
+// the ones before it, so every occurrence found is a real one.
+pub fn prefix_extraction(start: usize, states: &mut Vec<State>) -> (usize, String, bool, Vec<State>) {
+    // Kept in order so the new start state's transitions, and with them the
+    // order threads are explored in, do not depend on hashing
+    let mut next_states: Vec<Transition> = Vec::new();
+    let mut curr_states: Vec<Transition> = Vec::new();
     let mut states: Vec<State> = states.clone();
     let mut seen: HashSet<usize> = HashSet::new();
     let mut new_start = start;
     let mut prefix:Vec<char> = Vec::new();
+    let mut ignore_case = false;
+    let mut exact_case = false;
     let mut diff_transition = false;
 
-    let start_transition =Transition {c: None, state: Some(start)};
-    curr_states.insert(start_transition.clone());
-    next_states.insert(start_transition);
+    let start_transition =Transition {range: None, state: Some(start), assertion: None, tag: None};
+    curr_states.push(start_transition.clone());
+    next_states.push(start_transition);
     while !diff_transition && !next_states.is_empty(){
-        next_states = HashSet::new();
-        let mut transition: Option<char> = None;
+        next_states = Vec::new();
+        let mut chars: Vec<char> = Vec::new();
         for curr in curr_states.iter() {
             let state_loc = curr.state.unwrap();
             let curr_state = &states[state_loc];
+            // A shorter match would be lost if the prefix grew past it
+            if curr_state.is_match {
+                diff_transition = true;
+            }
             for out in curr_state.out.iter() {
-                let c = out.c.unwrap();
-                if transition == None {
-                    transition = Some(c);
-                }
-                else if transition.unwrap() != c {
-                    diff_transition = true
+                // Assertions depend on the surrounding text, so the prefix
+                // cannot extend past one, nor past a choice of characters
+                let c = match out.range {
+                    Some((lo, hi)) if lo == hi => lo,
+                    _ => {
+                        diff_transition = true;
+                        continue;
+                    }
+                };
+                if !chars.contains(&c) {
+                    chars.push(c);
                 }
                 if !seen.contains(&out.state.unwrap()) {
-                    next_states.insert(out.clone());
+                    next_states.push(out.clone());
                     seen.insert(out.state.unwrap());
                 }
             }
         }
-        if !diff_transition && transition != None && !next_states.is_empty() {
-            prefix.push(transition.unwrap());
-            curr_states = next_states.clone();
+        let caseless = |c: &char| c.to_lowercase().eq([*c]) && c.to_uppercase().eq([*c]);
+        let transition = match chars.as_slice() {
+            [c] if caseless(c) => Some(*c),
+            [c] if !ignore_case => {
+                exact_case = true;
+                Some(*c)
+            }
+            [a, b] if !exact_case && a.is_ascii_alphabetic() && a != b && a.eq_ignore_ascii_case(b) => {
+                ignore_case = true;
+                Some(a.to_ascii_lowercase())
+            }
+            [] => None,
+            _ => {
+                diff_transition = true;
+                None
+            }
+        };
+        if let Some(t) = transition {
+            if !diff_transition && !next_states.is_empty() {
+                prefix.push(t);
+                curr_states = next_states.clone();
+            }
         }
     }
 
-    if prefix.len() != 0 {
+    if !prefix.is_empty() {
         new_start = states.len();
         let mut is_match: bool = false;
         for curr in curr_states.iter() {
@@ -284,142 +721,335 @@ pub fn prefix_extraction(start: usize, states: &mut Vec<State>) -> (usize, Strin
                 is_match = true;
             }
         }
-        next_states = HashSet::new();
+        next_states = Vec::new();
         for curr in curr_states.iter() {
             let state_loc = curr.state.unwrap();
             let curr_state = &states[state_loc];
             for out in curr_state.out.iter() {
-                    next_states.insert(out.clone());
+                if !next_states.contains(out) {
+                    next_states.push(out.clone());
                 }
             }
-        let mut new_state = split_state_with_transitions(next_states.into_iter().collect());
+        }
+        let mut new_state = split_state_with_transitions(next_states);
         new_state.is_match = is_match;
         states.push(new_state);
     }
 
-    return (new_start, prefix.into_iter().collect(), states);
+    let prefix: String = prefix.into_iter().collect();
+    let prefix = if ignore_case { prefix.to_ascii_lowercase() } else { prefix };
+    return (new_start, prefix, ignore_case, states);
 }
 
-pub fn matching(start: usize, states: &mut Vec<State>, string: &str, prefix_length: usize, match_starts: Vec<usize>) -> Vec<String> {
-    let mut curr_states: HashSet<(usize, usize, usize)> = HashSet::new();
-    let mut match_substr: Vec<Vec<(usize, usize)>> = Vec::new();
-    let mut linenum = 1;
+// A thread of the NFA simulation: the position its match started at and the
+// capture slots it has recorded on the way
+#[derive(Debug, Clone)]
+struct Thread {
+    start: usize,
+    slots: Vec<Option<usize>>,
+}
 
-    match_substr.push(Vec::new());
-    let mut match_starts_count = 0;
+impl Thread {
+    fn tagged(&self, slot: usize, pos: usize) -> Thread {
+        let mut thread = self.clone();
+        if thread.slots.len() <= slot {
+            thread.slots.resize(slot + 1, None);
+        }
+        thread.slots[slot] = Some(pos);
+        return thread;
+    }
+}
 
-    // Iterate through the string, moving forward in all present states as necessary
-    for (i, c) in string.chars().enumerate() {
-        // Adds start state of NFA
-        // if there is no prefix, do so at every index
-        // if there is an index, do so only where that prefix has been identified
-        if prefix_length > 0 {
-            if !match_starts.is_empty() && match_starts_count != match_starts.len(){
-                if i == match_starts[match_starts_count] {
-                    curr_states.insert((start, linenum, i - prefix_length));
-                    match_starts_count += 1;
-                }
+// The live threads in priority order, at most one per state. When two threads
+// meet the one that started earliest wins, and on a tie the one that got there
+// first, which keeps the recorded captures independent of hashing.
+#[derive(Default)]
+struct Threads {
+    list: Vec<(usize, Thread)>,
+    index: HashMap<usize, usize>,
+}
+
+impl Threads {
+    // Returns whether the thread was added or replaced a later one
+    fn add(&mut self, state: usize, thread: Thread) -> bool {
+        match self.index.get(&state) {
+            Some(&i) if self.list[i].1.start <= thread.start => false,
+            Some(&i) => {
+                self.list[i].1 = thread;
+                true
+            }
+            None => {
+                self.index.insert(state, self.list.len());
+                self.list.push((state, thread));
+                true
             }
         }
-        else {
-            curr_states.insert((start, linenum, i));
+    }
+
+    fn retain_started_by(&mut self, pos: usize) {
+        self.list.retain(|(_, thread)| thread.start <= pos);
+        self.index = self.list.iter().enumerate().map(|(i, (state, _))| (*state, i)).collect();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+}
+
+// Follows every zero-width transition out of the current threads: assertions
+// that hold between `prev` and `next`, and tags, which record `pos` in a
+// capture slot
+fn zero_width_closure(threads: &mut Threads, states: &[State], pos: usize, prev: Option<char>, next: Option<char>) {
+    let mut queue: VecDeque<usize> = threads.list.iter().map(|(state, _)| *state).collect();
+    while let Some(state) = queue.pop_front() {
+        let thread = threads.list[threads.index[&state]].1.clone();
+        for transition in states[state].out.iter() {
+            let next_thread = match (transition.assertion, transition.tag) {
+                (Some(assertion), _) if assertion.holds(prev, next) => thread.clone(),
+                (None, Some(slot)) => thread.tagged(slot, pos),
+                _ => continue,
+            };
+            if threads.add(transition.state.unwrap(), next_thread) {
+                queue.push_back(transition.state.unwrap());
+            }
         }
+    }
+}
+
+// Whether the NFA from `start` reaches a match state without consuming a
+// character, between the characters `prev` and `next`
+pub fn accepts_empty(start: usize, states: &[State], prev: Option<char>, next: Option<char>) -> bool {
+    let mut threads = Threads::default();
+    threads.add(start, Thread { start: 0, slots: Vec::new() });
+    zero_width_closure(&mut threads, states, 0, prev, next);
+    return threads.list.iter().any(|(state, _)| states[*state].is_match);
+}
 
-        // Newline logic
-        if c == '\n'{
-            for curr in curr_states.iter() {
-                let curr_str_start = curr.2;
-                let curr_state = &states[curr.0];
-                if curr_state.is_match {
-                    match_substr[linenum-1].push((curr_str_start, i));
+// Runs the NFA from `start` on a match beginning at byte `from`, handing
+// `visit` each position with the threads there, zero-width transitions
+// followed, until it returns false or no thread is left
+fn run_anchored<F: FnMut(usize, &Threads) -> bool>(start: usize, states: &[State], string: &str, from: usize, mut visit: F) {
+    let mut threads = Threads::default();
+    threads.add(start, Thread { start: from, slots: Vec::new() });
+    let mut i = from;
+    let mut prev = string[..from].chars().next_back();
+    loop {
+        let next = string[i..].chars().next();
+        zero_width_closure(&mut threads, states, i, prev, next);
+        if !visit(i, &threads) {
+            return;
+        }
+        let c = match next {
+            None => return,
+            Some(c) => c,
+        };
+        let mut next_threads = Threads::default();
+        for (state, thread) in threads.list.iter() {
+            for transition in states[*state].out.iter() {
+                if transition.matches(c) {
+                    next_threads.add(transition.state.unwrap(), thread.clone());
                 }
             }
-            match_substr.push(Vec::new());
-            linenum += 1;
-            curr_states = HashSet::new();
-            continue;
         }
+        if next_threads.is_empty() {
+            return;
+        }
+        threads = next_threads;
+        prev = Some(c);
+        i += c.len_utf8();
+    }
+}
 
+// Every end of a match of the NFA from `start` that begins at byte `from`,
+// shortest first, `from` itself included if the empty string matches there
+pub fn match_ends_at(start: usize, states: &[State], string: &str, from: usize) -> Vec<usize> {
+    let mut ends = Vec::new();
+    run_anchored(start, states, string, from, |i, threads| {
+        if threads.list.iter().any(|(state, _)| states[*state].is_match) {
+            ends.push(i);
+        }
+        return true;
+    });
+    return ends;
+}
 
-        // If there exists a transition in any current state using the current 
-        // character, traverse it, otherwise drop the state
-        let mut next_states = HashSet::new();
-        for curr in curr_states.iter() {
-            let curr_str_start = curr.2;
-            let curr_state = &states[curr.0];
-            for transition in curr_state.out.iter() {
-                if transition.c.unwrap() == c {
-                    next_states.insert((transition.state.unwrap(), linenum, curr_str_start));
+// The capture slots of a match of the NFA from `start` that begins at byte
+// `from` and ends at byte `end`, recorded by the thread find_at would keep,
+// or None if no match ends there
+pub fn match_slots_at(start: usize, states: &[State], string: &str, from: usize, end: usize) -> Option<Vec<Option<usize>>> {
+    let mut slots = None;
+    run_anchored(start, states, string, from, |i, threads| {
+        if i == end {
+            let thread = threads.list.iter().find(|(state, _)| states[*state].is_match);
+            slots = thread.map(|(_, thread)| thread.slots.clone());
+        }
+        return i < end;
+    });
+    return slots;
+}
+
+// The earliest start of a match attempt from byte `from` on that is still
+// going where `string` ends, so that text after it could make it match or
+// match further. Attempts begin where find_at begins them, and when two meet
+// the earlier one is kept, so none is lost. Only starts are tracked, which
+// keeps this cheap enough to run over every chunk of a multiline search.
+pub fn open_match_start(start: usize, states: &[State], string: &str, from: usize, prefix_length: usize, match_starts: &[usize]) -> Option<usize> {
+    // The live threads as (state, start), earliest start first, with the
+    // position each state last had a thread queued at and the one it was
+    // last reached at by the closure, so each happens once per position
+    let mut threads: Vec<(usize, usize)> = Vec::new();
+    let mut queued: Vec<usize> = vec![usize::MAX; states.len()];
+    let mut taken: Vec<usize> = vec![usize::MAX; states.len()];
+    let mut closed: Vec<(usize, usize)> = Vec::new();
+    let mut stack: Vec<usize> = Vec::new();
+    let mut match_starts_count = match_starts.partition_point(|&p| p < from + prefix_length);
+    let mut i = from;
+    let mut prev = string[..from].chars().next_back();
+    loop {
+        // With nothing in flight, skip straight to the next prefix occurrence
+        if prefix_length > 0 && threads.is_empty() {
+            let next_start = *match_starts.get(match_starts_count)?;
+            if next_start > i {
+                i = next_start;
+                prev = string[..i].chars().next_back();
+            }
+        }
+        let next = string[i..].chars().next();
+        // A new attempt starts later than any in flight, so it goes last
+        let fresh = match prefix_length {
+            0 => Some(i),
+            _ if match_starts.get(match_starts_count) == Some(&i) => {
+                match_starts_count += 1;
+                Some(i - prefix_length)
+            }
+            _ => None,
+        };
+        if let Some(fresh) = fresh.filter(|_| queued[start] != i) {
+            queued[start] = i;
+            threads.push((start, fresh));
+        }
+        let c = match next {
+            // What comes next is not known, so any thread that has somewhere
+            // to go is still going, except one that has not begun
+            None => return threads.iter().filter(|(state, s)| !states[*state].out.is_empty() && *s < i).map(|(_, s)| *s).min(),
+            Some(c) => c,
+        };
+        // Follow the zero-width transitions of each thread in turn, so a
+        // state is taken by the earliest thread that reaches it
+        closed.clear();
+        for (state, thread_start) in threads.iter().copied() {
+            if taken[state] == i {
+                continue;
+            }
+            taken[state] = i;
+            stack.push(state);
+            while let Some(state) = stack.pop() {
+                closed.push((state, thread_start));
+                for transition in states[state].out.iter() {
+                    let follow = match (transition.assertion, transition.tag) {
+                        (Some(assertion), _) => assertion.holds(prev, next),
+                        (None, Some(_)) => true,
+                        _ => false,
+                    };
+                    let target = transition.state.unwrap();
+                    if follow && taken[target] != i {
+                        taken[target] = i;
+                        stack.push(target);
+                    }
                 }
             }
-            if curr_state.is_match {
-                match_substr[linenum-1].push((curr_str_start, i));
+        }
+        let after = i + c.len_utf8();
+        threads.clear();
+        for (state, thread_start) in closed.iter().copied() {
+            for transition in states[state].out.iter() {
+                let target = transition.state.unwrap();
+                if transition.matches(c) && queued[target] != after {
+                    queued[target] = after;
+                    threads.push((target, thread_start));
+                }
             }
         }
-        curr_states = next_states;
+        prev = Some(c);
+        i = after;
     }
+}
 
-    // If any current state is a match state, add the substring so far to the 
-    // list of matches
-    for curr in curr_states.iter() {
-        let curr_state = &states[curr.0];
-        let curr_str_start = curr.2;
-        let curr_str_line = curr.1;
-        if curr_state.is_match {
-            match_substr[curr_str_line-1].push((curr_str_start, string.len()));
-        }
-    }
+// Finds the leftmost-longest non-empty match starting at or after byte `from`,
+// returned as a byte range along with the capture slots of the thread that
+// made it. Only a pattern that can match a newline, in multiline mode, has
+// matches that cross one.
+// With a literal prefix, `start` is the state following the prefix and threads
+// are only started at the positions in `match_starts`, where the prefix ends.
+pub fn find_at(start: usize, states: &[State], string: &str, from: usize, prefix_length: usize, match_starts: &[usize]) -> Option<(usize, usize, Vec<Option<usize>>)> {
+    let mut threads = Threads::default();
+    let mut best: Option<(usize, usize, Vec<Option<usize>>)> = None;
+    let mut match_starts_count = match_starts.partition_point(|&p| p < from + prefix_length);
+    let mut i = from;
+    let mut prev = string[..from].chars().next_back();
 
-    // If a match is just the prefix, this handles the case if the prefix is the
-    // last n characters of the string
-    if !match_starts.is_empty() {
-        if match_starts[match_starts.len()-1] == string.len(){
-            match_substr[linenum-1].push((string.len() - prefix_length, string.len()));
+    loop {
+        // With nothing in flight, skip straight to the next prefix occurrence
+        if prefix_length > 0 && threads.is_empty() && best.is_none() {
+            let next_start = *match_starts.get(match_starts_count)?;
+            if next_start > i {
+                i = next_start;
+                prev = string[..i].chars().next_back();
+            }
         }
-    }
+        let next = string[i..].chars().next();
 
-    // Remove overlapping matches, preferring the longest match
-    let mut matching: Vec<Vec<(usize, usize)>> = Vec::new();
-    let mut curr_line = 0;
-    for line in match_substr.iter_mut(){
-        if line.is_empty(){
-            curr_line += 1;
-            matching.push(Vec::new());
-            continue;
+        // Adds start state of NFA
+        // if there is no prefix, do so at every index
+        // if there is a prefix, do so only where that prefix has been identified
+        // Once a match is found, anything starting later cannot beat it
+        if best.is_none() {
+            if prefix_length == 0 {
+                threads.add(start, Thread { start: i, slots: Vec::new() });
+            }
+            else if match_starts.get(match_starts_count) == Some(&i) {
+                threads.add(start, Thread { start: i - prefix_length, slots: Vec::new() });
+                match_starts_count += 1;
+            }
         }
-        matching.push(Vec::new());
-        line.sort_by_key(|k| k.0);
-        let match_len = line.len();
-        matching[curr_line].push(line[0]);
-        for i in 1..match_len {
-            let curr_len = matching[curr_line].len()-1;
-            let curr = matching[curr_line][curr_len];
-            if curr.1 <= line[i].0{
-                matching[curr_line].push(line[i]);
+        zero_width_closure(&mut threads, states, i, prev, next);
+
+        // Prefer the match that starts first, then the longest one
+        for (state, thread) in threads.list.iter() {
+            if states[*state].is_match && thread.start < i {
+                let better = match best {
+                    Some((s, e, _)) => thread.start < s || (thread.start == s && i > e),
+                    None => true,
+                };
+                if better {
+                    best = Some((thread.start, i, thread.slots.clone()));
+                }
             }
-            else if curr.1 < line[i].1{
-                let curr = (line[i].0, line[i].1);
-                matching[curr_line].pop();
-                matching[curr_line].push(curr);
+        }
+        if let Some((s, _, _)) = best {
+            threads.retain_started_by(s);
+            if threads.is_empty() {
+                return best;
             }
         }
-        curr_line += 1;
-    }
 
-    // Correctly formats the match strings and returns them
-    let mut result: Vec<String> = Vec::new();
-    let mut curr_line = 1;
-    for line in matching.iter() {
-        for pair in line.iter() {
-            let start = pair.0;
-            let end = pair.1;
-            let matching_str = (&string)[start..end].to_string();
-            if !matching_str.is_empty(){
-                result.push(format!("{}:{}", curr_line, matching_str));
+        let c = match next {
+            None => return best,
+            Some(c) => c,
+        };
+
+        // If there exists a transition in any current state using the current
+        // character, traverse it, otherwise drop the state
+        let mut next_threads = Threads::default();
+        for (state, thread) in threads.list.iter() {
+            for transition in states[*state].out.iter() {
+                if transition.matches(c) {
+                    next_threads.add(transition.state.unwrap(), thread.clone());
+                }
             }
         }
-        curr_line += 1;
+        threads = next_threads;
+        prev = Some(c);
+        i += c.len_utf8();
     }
-    
-    return result;
-}
\ No newline at end of file
+}
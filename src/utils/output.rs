@@ -0,0 +1,236 @@
+use std::io::{self, Write};
+
+use super::cli::Options;
+use super::json::Event;
+use super::search::LineMatch;
+
+// The SGR sequences output is colored with, GREP_COLORS style: matches in
+// selected and context lines, the rest of those lines, file names, line
+// numbers and separators. An empty sequence leaves that part uncolored.
+// `erase` adds the clear-to-end-of-line GNU grep writes after each one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Colors {
+    pub selected_match: String,
+    pub context_match: String,
+    pub selected_line: String,
+    pub context_line: String,
+    pub filename: String,
+    pub line_number: String,
+    pub separator: String,
+    pub erase: bool,
+}
+
+// GNU grep's colors
+impl Default for Colors {
+    fn default() -> Self {
+        Colors {
+            selected_match: "01;31".to_string(),
+            context_match: "01;31".to_string(),
+            selected_line: String::new(),
+            context_line: String::new(),
+            filename: "35".to_string(),
+            line_number: "32".to_string(),
+            separator: "36".to_string(),
+            erase: true,
+        }
+    }
+}
+
+impl Colors {
+    // No color at all, for plain output
+    pub fn none() -> Colors {
+        Colors {
+            selected_match: String::new(),
+            context_match: String::new(),
+            selected_line: String::new(),
+            context_line: String::new(),
+            filename: String::new(),
+            line_number: String::new(),
+            separator: String::new(),
+            erase: false,
+        }
+    }
+
+    // The default colors changed by a GREP_COLORS value such as
+    // `ms=01;32:fn=34:ne`. `mt` sets both match colors and `ne` drops the
+    // clear-to-end-of-line; other names and values that are not SGR
+    // parameters are ignored.
+    pub fn parse(spec: &str) -> Colors {
+        let mut colors = Colors::default();
+        for capability in spec.split(':') {
+            let (name, value) = match capability.split_once('=') {
+                Some((name, value)) => (name, value),
+                None => {
+                    if capability == "ne" {
+                        colors.erase = false;
+                    }
+                    continue;
+                }
+            };
+            if !value.chars().all(|c| c.is_ascii_digit() || c == ';') {
+                continue;
+            }
+            let value = value.to_string();
+            match name {
+                "mt" => {
+                    colors.selected_match = value.clone();
+                    colors.context_match = value;
+                }
+                "ms" => colors.selected_match = value,
+                "mc" => colors.context_match = value,
+                "sl" => colors.selected_line = value,
+                "cx" => colors.context_line = value,
+                "fn" => colors.filename = value,
+                "ln" => colors.line_number = value,
+                "se" => colors.separator = value,
+                _ => {}
+            }
+        }
+        return colors;
+    }
+
+    // Writes `text` in the color `sgr`, or as it is if either is empty. The
+    // text is written as bytes, since lines need not be UTF-8.
+    fn paint<W: Write>(&self, out: &mut W, sgr: &str, text: &[u8]) -> io::Result<()> {
+        if sgr.is_empty() || text.is_empty() {
+            return out.write_all(text);
+        }
+        let erase = if self.erase { "\x1b[K" } else { "" };
+        write!(out, "\x1b[{}m{}", sgr, erase)?;
+        out.write_all(text)?;
+        return write!(out, "\x1b[m{}", erase);
+    }
+}
+
+// Writes search results the way grep does: `file:number:text` with the file
+// name and line number only when asked for, the whole line or with -o each
+// match on a line of its own. Context lines are marked `file-number-text`,
+// and with context a `--` line separates groups of lines that are not
+// adjacent. Each part is colored as `colors` says, which is not at all
+// unless `with_colors` is used.
+pub struct Printer<W: Write> {
+    out: W,
+    colors: Colors,
+    show_names: bool,
+    line_number: bool,
+    only_matching: bool,
+    context: bool,
+    // The file and number of the last line printed, to know when a group
+    // ends
+    last: Option<(String, usize)>,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(out: W, options: &Options, show_names: bool) -> Printer<W> {
+        let context = options.after_context.or(options.before_context).or(options.context).is_some();
+        let colors = Colors::none();
+        Printer { out, colors, show_names, line_number: options.line_number, only_matching: options.only_matching, context, last: None }
+    }
+
+    pub fn with_colors(mut self, colors: Colors) -> Printer<W> {
+        self.colors = colors;
+        return self;
+    }
+
+    fn prefix(&mut self, filename: &str, number: usize, separator: char) -> io::Result<()> {
+        let separator = separator.to_string();
+        if self.show_names {
+            self.colors.paint(&mut self.out, &self.colors.filename, filename.as_bytes())?;
+            self.colors.paint(&mut self.out, &self.colors.separator, separator.as_bytes())?;
+        }
+        if self.line_number {
+            self.colors.paint(&mut self.out, &self.colors.line_number, number.to_string().as_bytes())?;
+            self.colors.paint(&mut self.out, &self.colors.separator, separator.as_bytes())?;
+        }
+        return Ok(());
+    }
+
+    fn group_separator(&mut self) -> io::Result<()> {
+        self.colors.paint(&mut self.out, &self.colors.separator, b"--")?;
+        return writeln!(self.out);
+    }
+
+    // Writes `--` if the line does not follow the last one printed. Line
+    // numbers only go up within a file, so a lower one starts another file
+    // even under the same name.
+    fn separate(&mut self, filename: &str, number: usize) -> io::Result<()> {
+        match &mut self.last {
+            Some((last_file, last_number)) if last_file == filename && number > *last_number => {
+                let adjacent = number == *last_number + 1;
+                *last_number = number;
+                if !adjacent {
+                    self.group_separator()?;
+                }
+            }
+            last => {
+                let first = last.is_none();
+                *last = Some((filename.to_string(), number));
+                if !first {
+                    self.group_separator()?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn line(&mut self, filename: &str, found: &LineMatch) -> io::Result<()> {
+        // -o has nothing to show of a context line
+        if found.context && self.only_matching {
+            return Ok(());
+        }
+        if self.context {
+            self.separate(filename, found.number)?;
+        }
+        let separator = if found.context { '-' } else { ':' };
+        let (line_color, match_color) = match found.context {
+            true => (&self.colors.context_line, &self.colors.context_match),
+            false => (&self.colors.selected_line, &self.colors.selected_match),
+        };
+        let (line_color, match_color) = (line_color.clone(), match_color.clone());
+        if !self.only_matching {
+            self.prefix(filename, found.number, separator)?;
+            let mut last = 0;
+            for (start, end) in found.spans.iter() {
+                self.colors.paint(&mut self.out, &line_color, &found.line[last..*start])?;
+                self.colors.paint(&mut self.out, &match_color, &found.line[*start..*end])?;
+                last = *end;
+            }
+            self.colors.paint(&mut self.out, &line_color, &found.line[last..])?;
+            return writeln!(self.out);
+        }
+        for (start, end) in found.spans.iter() {
+            self.prefix(filename, found.number, separator)?;
+            self.colors.paint(&mut self.out, &match_color, &found.line[*start..*end])?;
+            writeln!(self.out)?;
+        }
+        return Ok(());
+    }
+
+    // One JSON Lines event, for --json
+    pub fn event(&mut self, event: &Event) -> io::Result<()> {
+        return writeln!(self.out, "{}", event.to_json());
+    }
+
+    // The number of selected lines, for -c
+    pub fn count(&mut self, filename: &str, count: usize) -> io::Result<()> {
+        if self.show_names {
+            self.colors.paint(&mut self.out, &self.colors.filename, filename.as_bytes())?;
+            self.colors.paint(&mut self.out, &self.colors.separator, b":")?;
+        }
+        return writeln!(self.out, "{}", count);
+    }
+
+    // A file's name on its own, for -l and -L
+    pub fn path(&mut self, filename: &str) -> io::Result<()> {
+        self.colors.paint(&mut self.out, &self.colors.filename, filename.as_bytes())?;
+        return writeln!(self.out);
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        return self.out.flush();
+    }
+
+    pub fn into_inner(self) -> W {
+        return self.out;
+    }
+}
@@ -1,25 +1,402 @@
 use super::nfa::*;
+use super::dfa::*;
 use super::grammar::*;
 use super::earley_parse::*;
 use super::boyer_moore::*;
+use super::state_utils::*;
+use super::error::*;
 
-pub fn get_match(regex: &str, to_match: &str) -> Vec<String>{
-    let g = our_grammar();
-
-    let result = parse(regex, &g);
-    assert!(result.is_some());
-
-    let nfa = cfg2nfa(result.unwrap());
-    let mut start = nfa.0;
-    let mut states = nfa.1;
-    let prefix_ex = prefix_extraction(start, &mut states);
-    start = prefix_ex.0;
-    let prefix = prefix_ex.1;
-    states = prefix_ex.2;
-
-    let match_starts = string_search(to_match, &prefix);
-    // println!("Prefix: {:?}, Occur: {:?}", prefix, match_starts);
-    
-    let matching_str = matching(start, &mut states, to_match, prefix.len(), match_starts);
-    return matching_str;
+use std::fmt;
+use std::rc::Rc;
+
+// A compiled pattern that can be matched against any number of inputs.
+// Matches are leftmost-longest and never empty, and only cross a newline when
+// the pattern is compiled in multiline mode.
+#[derive(Debug, Clone)]
+pub struct Regex {
+    start: usize,
+    prefix: String,
+    prefix_ignore_case: bool,
+    states: Vec<State>,
+    names: GroupNames,
+    engine: Engine,
+    stats: Stats,
+    // Whether some match may contain a newline
+    multiline: bool,
+    // Whether the empty string matches between two characters, indexed by
+    // what each is: none at the ends of the input, a newline, or another
+    // character. Assertions tell positions apart no further.
+    empty_matches: [[bool; 3]; 3],
+}
+
+// What a compiled pattern costs. The DFA counts are for the unanchored DFA
+// then the anchored one, and are None when they did not fit and lazy DFAs
+// are used instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub nfa_states: usize,
+    pub dfa_states: Option<(usize, usize)>,
+    pub minimized_states: Option<(usize, usize)>,
+    pub dfa_state_limit: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "NFA states: {}", self.nfa_states)?;
+        match (self.dfa_states, self.minimized_states) {
+            (Some((search, anchored)), Some((min_search, min_anchored))) => {
+                writeln!(f, "DFA states: {} unanchored, {} anchored", search, anchored)?;
+                write!(f, "minimized DFA states: {} unanchored, {} anchored", min_search, min_anchored)
+            }
+            _ => write!(f, "DFA states: more than {}, using a lazy DFA", self.dfa_state_limit),
+        }
+    }
+}
+
+// The DFAs find runs on, both following the NFA from its original start. The
+// unanchored one finds where the first match ends, the anchored one how far a
+// match from a given start goes. Full DFAs are used when they fit in the
+// configured budget, lazy ones otherwise.
+#[derive(Debug, Clone)]
+enum Engine {
+    Dfa { search: Dfa, anchored: Dfa },
+    Lazy { search: LazyDfa, anchored: LazyDfa },
+}
+
+// A match of a `Regex`, as a byte range into the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'t> {
+    text: &'t str,
+    start: usize,
+    end: usize,
+}
+
+impl<'t> Match<'t> {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn as_str(&self) -> &'t str {
+        &self.text[self.start..self.end]
+    }
+}
+
+// The groups of a match, group 0 being the whole match. A group is None when
+// it took no part in the match.
+#[derive(Debug, Clone)]
+pub struct Captures<'t> {
+    text: &'t str,
+    groups: Vec<Option<(usize, usize)>>,
+    names: GroupNames,
+}
+
+impl<'t> Captures<'t> {
+    pub fn get(&self, i: usize) -> Option<Match<'t>> {
+        let (start, end) = (*self.groups.get(i)?)?;
+        Some(Match { text: self.text, start, end })
+    }
+
+    pub fn name(&self, name: &str) -> Option<Match<'t>> {
+        let i = self.names.iter().position(|n| n.as_deref() == Some(name))?;
+        self.get(i)
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+// Iterator over the successive non-overlapping matches in a string
+pub struct Matches<'r, 't> {
+    regex: &'r Regex,
+    text: &'t str,
+    match_starts: Vec<usize>,
+    pos: usize,
+}
+
+impl<'r, 't> Iterator for Matches<'r, 't> {
+    type Item = Match<'t>;
+
+    fn next(&mut self) -> Option<Match<'t>> {
+        let (start, end) = self.regex.find_with_starts(self.text, self.pos, &self.match_starts)?;
+        self.pos = end;
+        Some(Match { text: self.text, start, end })
+    }
+}
+
+impl Matches<'_, '_> {
+    // Continues the search from byte `pos` instead of the end of the last
+    // match, reusing the prefix occurrences already found
+    pub fn resume_at(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+}
+
+impl Regex {
+    pub fn new(regex: &str) -> Result<Regex, RegexError> {
+        compile(regex, &NfaConfig::default())
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.find(text).is_some()
+    }
+
+    pub fn find<'t>(&self, text: &'t str) -> Option<Match<'t>> {
+        self.find_at(text, 0)
+    }
+
+    // Like `find`, but only considers matches starting at or after byte `from`
+    pub fn find_at<'t>(&self, text: &'t str, from: usize) -> Option<Match<'t>> {
+        let match_starts = self.prefix_ends(text);
+        let (start, end) = self.find_with_starts(text, from, &match_starts)?;
+        Some(Match { text, start, end })
+    }
+
+    pub fn find_iter<'r, 't>(&'r self, text: &'t str) -> Matches<'r, 't> {
+        Matches { regex: self, text, match_starts: self.prefix_ends(text), pos: 0 }
+    }
+
+    pub fn captures<'t>(&self, text: &'t str) -> Option<Captures<'t>> {
+        self.captures_at(text, 0)
+    }
+
+    // Like `captures`, but only considers matches starting at or after byte
+    // `from`
+    pub fn captures_at<'t>(&self, text: &'t str, from: usize) -> Option<Captures<'t>> {
+        let match_starts = self.prefix_ends(text);
+        let (start, end, slots) = find_at(self.start, &self.states, text, from, self.prefix.len(), &match_starts)?;
+        Some(self.captures_from_slots(text, start, end, &slots))
+    }
+
+    // The groups of the match from byte `start` to byte `end`, which need
+    // not be the longest from there, as with the shorter ones -w may pick.
+    // None if no match runs from `start` to `end`.
+    pub fn captures_of<'t>(&self, text: &'t str, start: usize, end: usize) -> Option<Captures<'t>> {
+        // The NFA starts after the prefix, so it has to be there first
+        let after = start + self.prefix.len();
+        if !self.prefix.is_empty() && !self.prefix_ends(text).contains(&after) {
+            return None;
+        }
+        let slots = match_slots_at(self.start, &self.states, text, after, end)?;
+        Some(self.captures_from_slots(text, start, end, &slots))
+    }
+
+    fn captures_from_slots<'t>(&self, text: &'t str, start: usize, end: usize, slots: &[Option<usize>]) -> Captures<'t> {
+        let mut groups = vec![Some((start, end))];
+        for i in 1..self.names.len() {
+            let bounds = match (slots.get(2 * i), slots.get(2 * i + 1)) {
+                (Some(Some(s)), Some(Some(e))) => Some((*s, *e)),
+                _ => None,
+            };
+            groups.push(bounds);
+        }
+        Captures { text, groups, names: self.names.clone() }
+    }
+
+    // Number of groups including group 0
+    pub fn captures_len(&self) -> usize {
+        self.names.len()
+    }
+
+    // Name of each group in order, None for group 0 and unnamed groups
+    pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> {
+        self.names.iter().map(|n| n.as_deref())
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    // Whether a match may span several lines, which only happens when the
+    // pattern can match a newline
+    pub fn multiline(&self) -> bool {
+        self.multiline
+    }
+
+    // Whether the pattern can match the empty string, at least where its
+    // assertions hold
+    pub fn can_match_empty(&self) -> bool {
+        self.empty_matches.iter().flatten().any(|m| *m)
+    }
+
+    // Whether the pattern matches the empty string at byte `i` of `text`.
+    // Matches are never empty, so these are not found, but they still make
+    // a line match as with `^$` or `x*`.
+    pub fn matches_empty_at(&self, text: &str, i: usize) -> bool {
+        let kind = |c: Option<char>| match c {
+            None => 0,
+            Some('\n') => 1,
+            Some(_) => 2,
+        };
+        return self.empty_matches[kind(text[..i].chars().next_back())][kind(text[i..].chars().next())];
+    }
+
+    // Where the non-empty matches starting at byte `start` can end, shortest
+    // first. The longest is where a match found from there ends.
+    pub fn match_ends(&self, text: &str, start: usize) -> Vec<usize> {
+        let ends = match &self.engine {
+            Engine::Dfa { anchored, .. } => match_ends(anchored, text, start),
+            Engine::Lazy { anchored, .. } => match_ends(anchored, text, start),
+        };
+        if let Ok(ends) = ends {
+            return ends;
+        }
+        // The NFA starts after the prefix, so it has to be there first
+        let after = start + self.prefix.len();
+        if !self.prefix.is_empty() && !self.prefix_ends(text).contains(&after) {
+            return Vec::new();
+        }
+        let mut ends = match_ends_at(self.start, &self.states, text, after);
+        ends.retain(|&end| end > start);
+        return ends;
+    }
+
+    // Where the earliest match attempt from byte `from` on that is still
+    // going at the end of `text` began: one that more text could make match,
+    // or match further, or a literal prefix cut off by the end. None if no
+    // text after it could change the matches found.
+    pub fn open_match_start(&self, text: &str, from: usize) -> Option<usize> {
+        let open = open_match_start(self.start, &self.states, text, from, self.prefix.len(), &self.prefix_ends(text));
+        let starts_prefix = |rest: &str| match self.prefix_ignore_case {
+            true => self.prefix.starts_with(&rest.to_ascii_lowercase()),
+            false => self.prefix.starts_with(rest),
+        };
+        let first = (text.len() + 1).saturating_sub(self.prefix.len()).max(from);
+        let cut = (first..text.len()).find(|&p| text.is_char_boundary(p) && starts_prefix(&text[p..]));
+        return open.into_iter().chain(cut).min();
+    }
+
+    // Where each occurrence of the literal prefix ends
+    fn prefix_ends(&self, text: &str) -> Vec<usize> {
+        if self.prefix_ignore_case {
+            return string_search_ignore_case(text, &self.prefix);
+        }
+        return string_search(text, &self.prefix);
+    }
+
+    // Runs the DFAs, falling back to simulating the NFA if a lazy one gives up
+    fn find_with_starts(&self, text: &str, from: usize, match_starts: &[usize]) -> Option<(usize, usize)> {
+        let found = match &self.engine {
+            Engine::Dfa { search, anchored } => self.dfa_find(search, anchored, text, from, match_starts),
+            Engine::Lazy { search, anchored } => self.dfa_find(search, anchored, text, from, match_starts),
+        };
+        match found {
+            Ok(found) => found,
+            Err(GaveUp) => {
+                let (start, end, _) = find_at(self.start, &self.states, text, from, self.prefix.len(), match_starts)?;
+                Some((start, end))
+            }
+        }
+    }
+
+    fn dfa_find<A: Automaton>(&self, search: &A, anchored: &A, text: &str, from: usize, match_starts: &[usize]) -> Result<Option<(usize, usize)>, GaveUp> {
+        // Every match starts with the prefix, so only try where it occurs
+        if !self.prefix.is_empty() {
+            let first = match_starts.partition_point(|&p| p < from + self.prefix.len());
+            for p in match_starts[first..].iter() {
+                let start = p - self.prefix.len();
+                if let Some(end) = longest_match(anchored, text, start)? {
+                    return Ok(Some((start, end)));
+                }
+            }
+            return Ok(None);
+        }
+
+        let first_end = match earliest_match_end(search, text, from)? {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        // Without a newline in it the match starts on the line it ends on
+        let line_start = match self.multiline {
+            true => from,
+            false => text[from..first_end].rfind('\n').map_or(from, |i| from + i + 1),
+        };
+        for (i, _) in text[line_start..first_end].char_indices() {
+            if let Some(end) = longest_match(anchored, text, line_start + i)? {
+                return Ok(Some((line_start + i, end)));
+            }
+        }
+        return Ok(None);
+    }
+}
+
+// Parses a pattern, converts it to an epsilon-free NFA and pulls out the
+// literal prefix every match begins with
+pub fn compile(regex: &str, config: &NfaConfig) -> Result<Regex, RegexError> {
+    // The grammar has no empty regex, so it is a lone match state
+    let (nfa_start, mut states, names) = match regex.is_empty() {
+        true => (0, vec![match_state()], vec![None]),
+        false => cfg2nfa(try_parse(regex, &our_grammar())?, config)?,
+    };
+    let multiline = states.iter().flat_map(|s| s.out.iter()).any(|t| t.matches('\n'));
+    let (start, prefix, prefix_ignore_case, states) = prefix_extraction(nfa_start, &mut states);
+    let mut stats = Stats {
+        nfa_states: reachable_states(nfa_start, &states),
+        dfa_states: None,
+        minimized_states: None,
+        dfa_state_limit: config.dfa_state_limit,
+    };
+    let search = Dfa::build(&states, nfa_start, false, config.dfa_state_limit);
+    let anchored = Dfa::build(&states, nfa_start, true, config.dfa_state_limit);
+    let engine = match (search, anchored) {
+        (Some(search), Some(anchored)) => {
+            stats.dfa_states = Some((search.len(), anchored.len()));
+            let (search, anchored) = (search.minimize(), anchored.minimize());
+            stats.minimized_states = Some((search.len(), anchored.len()));
+            Engine::Dfa { search, anchored }
+        }
+        _ => {
+            let nfa: Rc<[State]> = states.clone().into();
+            Engine::Lazy {
+                search: LazyDfa::new(Rc::clone(&nfa), nfa_start, false, config.dfa_cache_limit),
+                anchored: LazyDfa::new(nfa, nfa_start, true, config.dfa_cache_limit),
+            }
+        }
+    };
+    let mut empty_matches = [[false; 3]; 3];
+    let sides = [None, Some('\n'), Some('a')];
+    for (prev, row) in sides.iter().zip(empty_matches.iter_mut()) {
+        for (next, empty_match) in sides.iter().zip(row.iter_mut()) {
+            *empty_match = prefix.is_empty() && accepts_empty(start, &states, *prev, *next);
+        }
+    }
+    return Ok(Regex { start, prefix, prefix_ignore_case, states, names, engine, stats, multiline, empty_matches });
+}
+
+// Compiles several patterns into one that matches wherever any of them does.
+// An error comes with the pattern it refers to, the one at fault when a
+// pattern is invalid on its own. An empty pattern matches every line, so
+// it is left out of the union, which then only gives the spans to report.
+pub fn compile_patterns(patterns: &[String], config: &NfaConfig) -> Result<Regex, (String, RegexError)> {
+    let non_empty: Vec<&String> = patterns.iter().filter(|p| !p.is_empty()).collect();
+    let empty_pattern = non_empty.len() < patterns.len();
+    if non_empty.len() <= 1 {
+        let pattern = non_empty.first().map_or("", |p| p.as_str());
+        let mut regex = compile(pattern, config).map_err(|e| (pattern.to_string(), e))?;
+        if empty_pattern {
+            regex.empty_matches = [[true; 3]; 3];
+        }
+        return Ok(regex);
+    }
+    let union = non_empty.iter().map(|p| format!("(?:{})", p)).collect::<Vec<String>>().join("|");
+    let mut regex = compile(&union, config).map_err(|e| {
+        for pattern in non_empty.iter() {
+            if let Err(own) = compile(pattern, config) {
+                return (pattern.to_string(), own);
+            }
+        }
+        (union.clone(), e)
+    })?;
+    if empty_pattern {
+        regex.empty_matches = [[true; 3]; 3];
+    }
+    return Ok(regex);
 }
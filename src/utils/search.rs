@@ -0,0 +1,456 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::{self, BufRead};
+
+use super::cli::Options;
+use super::regex::*;
+
+// How much of a file to read at once. A stream is searched as it arrives, a
+// buffer of whole lines at a time, so this also bounds how much is searched
+// at once, unless a line is longer.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// How much of the input a match that can cross lines may have to look at
+// before it is known where it ends. Those lines are kept in memory until
+// then, so past this the input is given up on.
+pub const MULTILINE_LIMIT: usize = 64 * 1024 * 1024;
+
+// A line selected by a search, or with `context` set a line printed around
+// one for -A, -B or -C. `line` is the line as it was read, which need not be
+// UTF-8, `start` is its byte offset in the input and `spans` the parts of it
+// that matched, in bytes from the start of the line, which is empty for
+// context lines and lines selected by -v. In multiline mode a match may run
+// over several lines, which are then selected together: `number` is the
+// first one's and `line` holds them all, without the last newline. When the
+// searcher is asked for them, `captures` has the bounds of groups 1 and up
+// of each span's match, found in the same text as the match itself, and is
+// empty otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineMatch<'t> {
+    pub number: usize,
+    pub start: usize,
+    pub line: &'t [u8],
+    pub spans: Vec<(usize, usize)>,
+    pub captures: Vec<Vec<Option<(usize, usize)>>>,
+    pub context: bool,
+}
+
+// Selects lines the way the command line asks: lines with a match, lines
+// with a whole-word or whole-line match, or with -v the lines without one.
+// Without a regex, as with an empty pattern file, nothing matches.
+pub struct Searcher<'r> {
+    regex: Option<&'r Regex>,
+    word: bool,
+    whole_line: bool,
+    invert: bool,
+    // Whether to find the groups of each match, for --json
+    captures: bool,
+    // Lines of context to hand over before and after each selected line
+    before: usize,
+    after: usize,
+    // How many bytes of lines a match that can cross lines may keep
+    multiline_limit: usize,
+}
+
+impl<'r> Searcher<'r> {
+    pub fn new(regex: Option<&'r Regex>, options: &Options) -> Searcher<'r> {
+        // Context only matters when lines are printed
+        let lines = !(options.count || options.files_with_matches || options.files_without_match || options.quiet);
+        let before = options.before_context.or(options.context).filter(|_| lines).unwrap_or(0);
+        let after = options.after_context.or(options.context).filter(|_| lines).unwrap_or(0);
+        Searcher { regex, word: options.word, whole_line: options.whole_line, invert: options.invert, captures: options.json, before, after, multiline_limit: MULTILINE_LIMIT }
+    }
+
+    pub fn with_multiline_limit(mut self, limit: usize) -> Searcher<'r> {
+        self.multiline_limit = limit;
+        return self;
+    }
+
+    pub fn regex(&self) -> Option<&'r Regex> {
+        return self.regex;
+    }
+
+    // The spans of `text` from byte `from` on to report: every match, only
+    // those that are whole words with -w, or with -x those that are a whole
+    // line
+    fn spans(&self, text: &str, from: usize) -> Vec<(usize, usize)> {
+        let regex = match self.regex {
+            Some(regex) => regex,
+            None => return Vec::new(),
+        };
+        if self.whole_line {
+            // The first match on a line is the leftmost-longest from its
+            // start, so it is the whole line if the line matches at all
+            let mut spans: Vec<(usize, usize)> = Vec::new();
+            let mut line_end = None;
+            let mut matches = regex.find_iter(text);
+            matches.resume_at(from);
+            for m in matches {
+                if line_end.is_some_and(|end| m.start() <= end) {
+                    continue;
+                }
+                let start = text[..m.start()].rfind('\n').map_or(0, |i| i + 1);
+                let end = text[m.end()..].find('\n').map_or(text.len(), |i| m.end() + i);
+                if m.start() == start && m.end() == end {
+                    spans.push((start, end));
+                }
+                line_end = Some(end);
+            }
+            return spans;
+        }
+        let mut matches = regex.find_iter(text);
+        matches.resume_at(from);
+        if !self.word {
+            return matches.map(|m| (m.start(), m.end())).collect();
+        }
+        let mut spans = Vec::new();
+        while let Some(m) = matches.next() {
+            let before = text[..m.start()].chars().next_back();
+            let ends_word = |end: usize| !text[end..].chars().next().is_some_and(is_word_char);
+            // A shorter match from the same start may end a word where the
+            // longest does not, as `foo` does for [a-z ]+ in `foo bar1`
+            let end = match before.is_some_and(is_word_char) {
+                true => None,
+                false if ends_word(m.end()) => Some(m.end()),
+                false => regex.match_ends(text, m.start()).into_iter().rev().find(|&end| end < m.end() && ends_word(end)),
+            };
+            match end {
+                Some(end) => {
+                    spans.push((m.start(), end));
+                    matches.resume_at(end);
+                }
+                // Try again from the next character on
+                None => matches.resume_at(m.start() + text[m.start()..].chars().next().unwrap().len_utf8()),
+            }
+        }
+        return spans;
+    }
+
+    // Every selected line of `text`, in order. The whole text is searched at
+    // once, so \A and \z keep meaning its start and end.
+    pub fn search<'t>(&self, text: &'t str) -> Vec<LineMatch<'t>> {
+        return self.select(text, 0);
+    }
+
+    // Whether the line `text[start..end]` matches only by an empty match,
+    // which is never among the spans: anywhere on the line, with -x only on
+    // an empty line, and with -w only where neither side is a word character
+    fn empty_match(&self, text: &str, start: usize, end: usize) -> bool {
+        let regex = match self.regex {
+            Some(regex) if regex.can_match_empty() => regex,
+            _ => return false,
+        };
+        if self.whole_line {
+            return start == end && regex.matches_empty_at(text, start);
+        }
+        let mut positions = text[start..end].char_indices().map(|(i, _)| start + i).chain([end]);
+        return positions.any(|i| {
+            let word = text[..i].chars().next_back().is_some_and(is_word_char) || text[i..].chars().next().is_some_and(is_word_char);
+            (!self.word || !word) && regex.matches_empty_at(text, i)
+        });
+    }
+
+    // The bounds of groups 1 and up of the match `text[s..e]`, made relative
+    // to the selected text `text[start..end]` and kept inside it like the
+    // span is
+    fn groups(&self, text: &str, s: usize, e: usize, start: usize, end: usize) -> Vec<Option<(usize, usize)>> {
+        let regex = self.regex.unwrap();
+        // A span is always one of the regex's matches
+        let captures = regex.captures_of(text, s, e).unwrap();
+        return (1..captures.len()).map(|i| captures.get(i).map(|m| (m.start().min(end) - start, m.end().min(end) - start))).collect();
+    }
+
+    // The selected lines of `text` from byte `from` on, which is 0 or just
+    // after a newline. Line numbers count from 1 and offsets from `from`.
+    fn select<'t>(&self, text: &'t str, from: usize) -> Vec<LineMatch<'t>> {
+        let mut selected: Vec<LineMatch> = Vec::new();
+        let mut spans = self.spans(text, from).into_iter().peekable();
+        let mut lines = text[from..].split_terminator('\n').enumerate();
+        let mut start = from;
+        while let Some((i, line)) = lines.next() {
+            let mut end = start + line.len();
+            let mut line_spans: Vec<(usize, usize)> = Vec::new();
+            let mut line_captures = Vec::new();
+            while let Some((s, e)) = spans.next_if(|(s, _)| *s <= end) {
+                // A match running past the newline takes the lines it covers
+                // along, up to the one its last character is on
+                while e > end + 1 {
+                    match lines.next() {
+                        Some((_, next)) => end += 1 + next.len(),
+                        None => break,
+                    }
+                }
+                line_spans.push((s - start, e.min(end) - start));
+                if self.captures && !self.invert {
+                    line_captures.push(self.groups(text, s, e, start, end));
+                }
+            }
+            let matched = !line_spans.is_empty() || self.empty_match(text, start, end);
+            if matched != self.invert {
+                let line_spans = if self.invert { Vec::new() } else { line_spans };
+                let line = &text.as_bytes()[start..end];
+                selected.push(LineMatch { number: i + 1, start: start - from, line, spans: line_spans, captures: line_captures, context: false });
+            }
+            start = end + 1;
+        }
+        return selected;
+    }
+
+    // Where the lines of `text`, searched from byte `from`, that more text
+    // could not change end: before the line on which a match that is still
+    // going at the end began, and before any lines selected together with
+    // that line as one match
+    fn settled(&self, text: &str, from: usize, selected: &[LineMatch]) -> usize {
+        let open = match self.regex.filter(|regex| regex.multiline()).and_then(|regex| regex.open_match_start(text, from)) {
+            Some(open) => open,
+            None => return text.len(),
+        };
+        let settled = text[..open].rfind('\n').map_or(0, |i| i + 1).max(from);
+        return match selected.iter().rev().find(|line| from + line.start < settled) {
+            Some(line) if from + line.start + line.line.len() >= settled => from + line.start,
+            _ => settled,
+        };
+    }
+
+    // Searches `reader` a chunk of whole lines at a time, handing each
+    // selected line and the context around it to `found` as soon as its
+    // chunk is searched, until the input ends or `found` returns false.
+    // Returns the number of selected lines handed over. Bytes that are not
+    // UTF-8 are searched as U+FFFD, but lines are handed over as they were
+    // read, with offsets and spans into the input's bytes.
+    pub fn search_reader<R: BufRead, F>(&self, reader: R, mut found: F) -> io::Result<usize>
+    where
+        F: FnMut(&LineMatch) -> bool,
+    {
+        return self.search_chunks(reader, |line| line.is_none_or(&mut found));
+    }
+
+    // Like search_reader, but after each chunk that handed over lines `found`
+    // is also called with None, so whatever was made of them can be flushed
+    // before waiting for more input.
+    //
+    // A chunk is whatever the reader has buffered up to its last newline,
+    // so lines are searched as soon as they arrive; only a line that is not
+    // complete yet is waited for.
+    //
+    // Unless the pattern can match a newline, matches never cross one, so the
+    // only state a chunk needs from the one before is that it does not begin
+    // the input: each chunk after the first is searched just after the
+    // previous newline, where \A cannot hold, and only the last one can end
+    // where \z does. The lines kept for -B and the count of lines still owed
+    // to -A carry over too. With a pattern that can match a newline, the
+    // lines from the one where a match may still be going on at the end of
+    // a chunk are kept and searched again in front of the next, until the
+    // match is over, which fails the search once they pass the multiline
+    // limit.
+    pub fn search_chunks<R: BufRead, F>(&self, mut reader: R, mut found: F) -> io::Result<usize>
+    where
+        F: FnMut(Option<&LineMatch>) -> bool,
+    {
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut lines_before = 0;
+        let mut offset = 0;
+        let mut selected = 0;
+        // The last lines not handed over, oldest first, as (number, start,
+        // line), reusing the buffers of lines that fall out
+        let mut before: VecDeque<(usize, usize, Vec<u8>)> = VecDeque::with_capacity(self.before);
+        let mut after = 0;
+        // Where the lines searched for the last time end in `bytes`
+        let mut settled = 0;
+        loop {
+            // Keep the lines still to search with the newline before them in
+            // front of this chunk
+            let from = match settled {
+                0 => 0,
+                _ => {
+                    bytes.drain(..settled - 1);
+                    1
+                }
+            };
+            // Read as much again as is kept for a long match, so its lines
+            // are not searched over and over, but not past the limit
+            let kept = bytes.len() - from;
+            let wanted = if kept > CHUNK_SIZE { (bytes.len() + kept).min(from + self.multiline_limit + 1) } else { 0 };
+            let mut eof = false;
+            loop {
+                let buffer = match reader.fill_buf() {
+                    Ok(buffer) => buffer,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                if buffer.is_empty() {
+                    eof = true;
+                    break;
+                }
+                // Leave a line that is not complete yet in the reader
+                let (taken, whole_lines) = match buffer.iter().rposition(|b| *b == b'\n') {
+                    Some(i) => (i + 1, true),
+                    None => (buffer.len(), false),
+                };
+                bytes.extend_from_slice(&buffer[..taken]);
+                reader.consume(taken);
+                if whole_lines && bytes.len() >= wanted {
+                    break;
+                }
+            }
+            if bytes.len() == from {
+                return Ok(selected);
+            }
+            let decoded = Decoded::new(&bytes);
+            let found_lines = self.select(&decoded.text, from);
+            let settled_text = match eof {
+                true => decoded.text.len(),
+                false => self.settled(&decoded.text, from, &found_lines),
+            };
+            settled = decoded.byte_offset(settled_text);
+            // Selected lines of the decoded text, moved back onto the bytes
+            let mut lines = found_lines.into_iter().take_while(|line| from + line.start < settled_text).map(|line| {
+                let text_start = from + line.start;
+                let start = decoded.byte_offset(text_start);
+                let end = decoded.byte_offset(text_start + line.line.len());
+                let to_bytes = |(s, e): &(usize, usize)| {
+                    (decoded.byte_offset(text_start + s) - start, decoded.byte_offset(text_start + e) - start)
+                };
+                let spans = line.spans.iter().map(to_bytes).collect();
+                let captures = line.captures.iter().map(|groups| groups.iter().map(|g| g.as_ref().map(to_bytes)).collect()).collect();
+                LineMatch { start: start - from, line: &bytes[start..end], spans, captures, ..line }
+            }).peekable();
+            // The lines of the chunk searched for the last time, which are the
+            // same in the text
+            let body_lines = bytes[from..settled].split_inclusive(|b| *b == b'\n').map(|line| line.strip_suffix(b"\n").unwrap_or(line));
+            let mut handed = false;
+            let mut hand = |line: &LineMatch| {
+                handed = true;
+                return found(Some(line));
+            };
+            if self.before == 0 && self.after == 0 {
+                for mut line in lines {
+                    line.number += lines_before;
+                    line.start += offset;
+                    selected += 1;
+                    if !hand(&line) {
+                        return Ok(selected);
+                    }
+                }
+            }
+            else {
+                // Go through every line to find the ones around a selected one
+                let mut start = 0;
+                // Lines already handed over as part of a multi-line match
+                let mut covered = 0;
+                for (i, line) in body_lines.clone().enumerate() {
+                    let (number, line_start) = (lines_before + i + 1, offset + start);
+                    start += line.len() + 1;
+                    if covered > 0 {
+                        covered -= 1;
+                    }
+                    else if let Some(mut selected_line) = lines.next_if(|l| l.number == i + 1) {
+                        for (number, start, line) in before.drain(..) {
+                            if !hand(&LineMatch { number, start, line: &line, spans: Vec::new(), captures: Vec::new(), context: true }) {
+                                return Ok(selected);
+                            }
+                        }
+                        selected_line.number = number;
+                        selected_line.start = line_start;
+                        covered = selected_line.line.iter().filter(|b| **b == b'\n').count();
+                        selected += 1;
+                        if !hand(&selected_line) {
+                            return Ok(selected);
+                        }
+                        after = self.after;
+                    }
+                    else if after > 0 {
+                        after -= 1;
+                        if !hand(&LineMatch { number, start: line_start, line, spans: Vec::new(), captures: Vec::new(), context: true }) {
+                            return Ok(selected);
+                        }
+                    }
+                    else if self.before > 0 {
+                        let mut kept = match before.len() == self.before {
+                            true => before.pop_front().unwrap().2,
+                            false => Vec::new(),
+                        };
+                        kept.clear();
+                        kept.extend_from_slice(line);
+                        before.push_back((number, line_start, kept));
+                    }
+                }
+            }
+            if handed && !found(None) {
+                return Ok(selected);
+            }
+            lines_before += body_lines.count();
+            offset += settled - from;
+            if eof {
+                return Ok(selected);
+            }
+            if bytes.len() - settled > self.multiline_limit {
+                let limit = match self.multiline_limit % (1 << 20) {
+                    0 => format!("{} MiB", self.multiline_limit >> 20),
+                    _ => format!("{} bytes", self.multiline_limit),
+                };
+                return Err(io::Error::other(format!("a match could run over more than {} of lines", limit)));
+            }
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    return c.is_alphanumeric() || c == '_';
+}
+
+// Text decoded from bytes that may not be UTF-8, each invalid sequence read
+// as U+FFFD, which can turn offsets into the text back into offsets into the
+// bytes and the other way round
+pub struct Decoded<'b> {
+    pub text: Cow<'b, str>,
+    // Where each U+FFFD put in ends, in the text then in the bytes
+    replaced: Vec<(usize, usize)>,
+}
+
+impl<'b> Decoded<'b> {
+    pub fn new(bytes: &'b [u8]) -> Decoded<'b> {
+        if let Ok(text) = std::str::from_utf8(bytes) {
+            return Decoded { text: Cow::Borrowed(text), replaced: Vec::new() };
+        }
+        let mut text = String::with_capacity(bytes.len());
+        let mut replaced = Vec::new();
+        let mut byte_end = 0;
+        for chunk in bytes.utf8_chunks() {
+            text.push_str(chunk.valid());
+            byte_end += chunk.valid().len() + chunk.invalid().len();
+            if !chunk.invalid().is_empty() {
+                text.push(char::REPLACEMENT_CHARACTER);
+                replaced.push((text.len(), byte_end));
+            }
+        }
+        return Decoded { text: Cow::Owned(text), replaced };
+    }
+
+    // The byte offset of offset `i` in the text, which is not inside a U+FFFD
+    // put in
+    pub fn byte_offset(&self, i: usize) -> usize {
+        let k = self.replaced.partition_point(|(text_end, _)| *text_end <= i);
+        return match k {
+            0 => i,
+            _ => i - self.replaced[k - 1].0 + self.replaced[k - 1].1,
+        };
+    }
+
+    // The offset in the text of byte offset `i`, which is not inside an
+    // invalid sequence
+    pub fn text_offset(&self, i: usize) -> usize {
+        let k = self.replaced.partition_point(|(_, byte_end)| *byte_end <= i);
+        return match k {
+            0 => i,
+            _ => i - self.replaced[k - 1].1 + self.replaced[k - 1].0,
+        };
+    }
+}
+
+// The lines of `to_match` with a match of `regex`. Panics if `regex` is not a
+// valid pattern, use `Regex::new` to handle the error.
+pub fn get_match<'t>(regex: &str, to_match: &'t str) -> Vec<LineMatch<'t>> {
+    let regex = Regex::new(regex).unwrap_or_else(|e| panic!("{}", e));
+    return Searcher::new(Some(&regex), &Options::default()).search(to_match);
+}
@@ -1,9 +1,54 @@
 use std::{collections::HashSet, hash::Hash};
 
+use super::unicode::case_fold_ranges;
+
+// Zero-width conditions on the position between two characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Assertion {
+    LineStart,
+    LineEnd,
+    TextStart,
+    TextEnd,
+}
+
+impl Assertion {
+    // `prev` and `next` are the characters either side of the position,
+    // None at the start or end of the input
+    pub fn holds(&self, prev: Option<char>, next: Option<char>) -> bool {
+        match self {
+            Assertion::LineStart => prev.is_none() || prev == Some('\n'),
+            Assertion::LineEnd => next.is_none() || next == Some('\n'),
+            Assertion::TextStart => prev.is_none(),
+            Assertion::TextEnd => next.is_none(),
+        }
+    }
+}
+
+// An inclusive range of characters
+pub type CharRange = (char, char);
+
+// A transition consumes any character in `range`, is guarded by a zero-width
+// `assertion`, records the current position in capture slot `tag`, or is an
+// epsilon transition when none of them are set
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Transition {
-    pub c: Option<char>,
-    pub state: Option<usize>
+    pub range: Option<CharRange>,
+    pub state: Option<usize>,
+    pub assertion: Option<Assertion>,
+    pub tag: Option<usize>,
+}
+
+impl Transition {
+    pub fn is_epsilon(&self) -> bool {
+        self.range.is_none() && self.assertion.is_none() && self.tag.is_none()
+    }
+
+    pub fn matches(&self, c: char) -> bool {
+        match self.range {
+            Some((lo, hi)) => lo <= c && c <= hi,
+            None => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -13,14 +58,14 @@ pub struct State {
 }
 
 pub fn transition_state(c: char, out: Option<usize>) -> State {
-    State { out: vec![Transition {c: Some(c), state: out}], is_match: false}
+    State { out: vec![Transition {range: Some((c, c)), state: out, assertion: None, tag: None}], is_match: false}
 
 }
 
 pub fn split_state(out: Vec<Option<usize>>) -> State {
     let mut new_out: Vec<Transition> = Vec::new();
     for curr_out in out.iter() {
-        new_out.push(Transition {c: None, state: *curr_out});
+        new_out.push(Transition {range: None, state: *curr_out, assertion: None, tag: None});
     }
     State {
         out: new_out,
@@ -30,19 +75,11 @@ pub fn split_state(out: Vec<Option<usize>>) -> State {
 
 pub fn split_state_with_transitions(out: Vec<Transition>) -> State {
     State {
-        out: out,
+        out,
         is_match: false
     }
 }
 
-pub fn merge_non_match_states(states: Vec<State>) -> State {
-    let mut new_out: Vec<Transition> = Vec::new();
-    for curr_state in states.iter() {
-        new_out.extend(curr_state.out.clone()); 
-    }
-    State { out: new_out, is_match: false}
-}
-
 pub fn match_state() -> State {
     State{
         out: Vec::new(),
@@ -55,118 +92,157 @@ pub fn patch(e1: usize, e2: usize, state_vec: &mut Vec<State>) {
     let mut seen: HashSet<usize> = HashSet::new();
     stack.push(e1);
 
-    while !stack.is_empty() {
-        let e: usize = stack.pop().unwrap();
+    while let Some(e) = stack.pop() {
         if e != e2 {
             let state: &mut State = &mut state_vec[e];
             let out = &mut state.out;
             
             for curr_out in out.iter_mut() {
-                if curr_out.state == None{
-                    curr_out.state = Some(e2);
-                }
-                else {
-                    let next_state = curr_out.state.unwrap();
-                    if !seen.contains(&next_state) {
-                        stack.push((curr_out.state).unwrap());
+                match curr_out.state {
+                    None => curr_out.state = Some(e2),
+                    Some(next_state) => {
+                        if !seen.contains(&next_state) {
+                            stack.push(next_state);
+                        }
                     }
-                } 
+                }
             }
             seen.insert(e);
         }
     }
 }
-pub fn terminal_state_from_char(c: char, states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
+// With `fold` set the state matches every case variant of `c`
+pub fn terminal_state_from_char(c: char, fold: bool, states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
+    if fold {
+        states_from_ranges(case_fold_ranges(vec![(c, c)]), states, frag_stack);
+        return;
+    }
     let state = transition_state(c, None);
     frag_stack.push(states.len());
     states.push(state);
-} 
+}
 
-pub fn let_states(states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
-    let mut out: Vec<Transition> = Vec::new();
-    for n in 0x41..0x5b {
-        let option = char::from_u32(n);
-        let c = option.unwrap();
-        out.push(Transition {c: Some(c), state: None});
-    }
-    for n in 0x61..0x7b {
-        let option = char::from_u32(n);
-        let c = option.unwrap();
-        out.push(Transition {c: Some(c), state: None});
-    }
-    let new_state = split_state_with_transitions(out);
+pub fn assertion_state(assertion: Assertion, states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
+    let state = State { out: vec![Transition {range: None, state: None, assertion: Some(assertion), tag: None}], is_match: false};
     frag_stack.push(states.len());
-    states.push(new_state);
+    states.push(state);
 }
 
-pub fn dgt_states(states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
-    let mut out: Vec<Transition> = Vec::new();
-    for n in 0x30..0x3a {
-        let option = char::from_u32(n);
-        let c = option.unwrap();
-        out.push(Transition {c: Some(c), state: None});
-    }
-    let new_state = split_state_with_transitions(out);
-    frag_stack.push(states.len());
-    states.push(new_state);
+// Capture group `group` records its start in slot 2 * group and its end in the
+// slot after. The group's fragment starts at `e`, the returned entry opens it.
+pub fn capture_states(group: usize, e: usize, states: &mut Vec<State>) -> usize {
+    let close = states.len();
+    states.push(State { out: vec![Transition {range: None, state: None, assertion: None, tag: Some(2 * group + 1)}], is_match: false});
+    patch(e, close, states);
+    let open = states.len();
+    states.push(State { out: vec![Transition {range: None, state: Some(e), assertion: None, tag: Some(2 * group)}], is_match: false});
+    return open;
 }
 
-pub fn ws_states(states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
-    let mut out: Vec<Transition> = Vec::new();
+// Letters and, below, tab and space are what \s and \w used to match, and
+// still do with --posix-classes
+pub fn let_ranges() -> Vec<CharRange> {
+    return vec![('A', 'Z'), ('a', 'z')];
+}
 
-    out.push(Transition {c: char::from_u32(0x09), state: None});
-    out.push(Transition {c: char::from_u32(0x20), state: None});
+pub fn dgt_ranges() -> Vec<CharRange> {
+    return vec![('0', '9')];
+}
 
-    let new_state = split_state_with_transitions(out);
-    frag_stack.push(states.len());
-    states.push(new_state);
+pub fn ws_ranges() -> Vec<CharRange> {
+    return vec![('\t', '\t'), (' ', ' ')];
 }
 
+// Perl's \s: tab, newline, vertical tab, form feed, carriage return and space
+pub fn space_ranges() -> Vec<CharRange> {
+    return vec![('\t', '\r'), (' ', ' ')];
+}
 
-pub fn sp_states(states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
-    let mut out: Vec<Transition> = Vec::new();
+// Perl's \w: ASCII letters, digits and underscore
+pub fn word_ranges() -> Vec<CharRange> {
+    return vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+}
 
-    for n in 0x21..0x30 {
-        let option = char::from_u32(n);
-        let c = option.unwrap();
-        out.push(Transition {c: Some(c), state: None});
-    }
+// Every character a pattern can match outside multiline mode: any Unicode
+// scalar value but the newline, which ends a line. Negated classes are taken
+// relative to this set.
+pub fn all_ranges() -> Vec<CharRange> {
+    return vec![('\0', '\t'), ('\u{b}', '\u{d7ff}'), ('\u{e000}', char::MAX)];
+}
 
-    for n in 0x3a..0x41 {
-        let option = char::from_u32(n);
-        let c = option.unwrap();
-        out.push(Transition {c: Some(c), state: None});
+// Sorts the ranges and merges the ones that overlap or touch
+pub fn normalize_ranges(mut ranges: Vec<CharRange>) -> Vec<CharRange> {
+    ranges.sort();
+    let mut merged: Vec<CharRange> = Vec::new();
+    for (lo, hi) in ranges {
+        match merged.last_mut() {
+            Some(last) if lo as u32 <= last.1 as u32 + 1 => last.1 = last.1.max(hi),
+            _ => merged.push((lo, hi)),
+        }
     }
+    return merged;
+}
 
-    for n in 0x5b..0x61 {
-        let option = char::from_u32(n);
-        let c = option.unwrap();
-        out.push(Transition {c: Some(c), state: None});
+// The characters of `all_ranges` outside `ranges`
+pub fn negate_ranges(ranges: Vec<CharRange>) -> Vec<CharRange> {
+    let ranges = normalize_ranges(ranges);
+    let mut rest: Vec<CharRange> = Vec::new();
+    for (lo, hi) in all_ranges() {
+        let mut next = lo as u32;
+        for (r_lo, r_hi) in ranges.iter() {
+            let (r_lo, r_hi) = (*r_lo as u32, *r_hi as u32);
+            if r_hi < next || r_lo > hi as u32 {
+                continue;
+            }
+            if r_lo > next {
+                rest.push((char::from_u32(next).unwrap(), char::from_u32(r_lo - 1).unwrap()));
+            }
+            next = r_hi + 1;
+        }
+        if next <= hi as u32 {
+            rest.push((char::from_u32(next).unwrap(), hi));
+        }
     }
+    return rest;
+}
 
-    for n in 0x7b..0x80 {
-        let option = char::from_u32(n);
-        let c = option.unwrap();
-        out.push(Transition {c: Some(c), state: None});
+// The characters outside `ranges`, including the newline when `newline` is
+// set and `ranges` does not hold it, as in multiline mode
+pub fn complement_ranges(ranges: Vec<CharRange>, newline: bool) -> Vec<CharRange> {
+    let excluded = ranges.iter().any(|(lo, hi)| *lo <= '\n' && '\n' <= *hi);
+    let mut rest = negate_ranges(ranges);
+    if newline && !excluded {
+        rest.push(('\n', '\n'));
+        rest = normalize_ranges(rest);
     }
+    return rest;
+}
 
+pub fn states_from_ranges(ranges: Vec<CharRange>, states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
+    let mut out: Vec<Transition> = Vec::new();
+    for range in ranges {
+        out.push(Transition {range: Some(range), state: None, assertion: None, tag: None});
+    }
     let new_state = split_state_with_transitions(out);
     frag_stack.push(states.len());
     states.push(new_state);
 }
 
-pub fn create_dot_state(states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
-    let_states(states, frag_stack);
-    dgt_states(states, frag_stack);
-    ws_states(states, frag_stack);
-    sp_states(states, frag_stack);
-
-    let mut states_vec: Vec<State> = Vec::new();
-    for _ in 0..4 {
-        let curr_frag = frag_stack.pop().unwrap();
-        states_vec.push(states[curr_frag].clone());
+// A single state with one transition per range of a bracket expression.
+// For a negated class the transitions cover every character not in `ranges`,
+// with `fold` set case variants are added before negating, and with `newline`
+// set the newline is among the characters not in `ranges`.
+pub fn class_states(ranges: Vec<CharRange>, negated: bool, fold: bool, newline: bool, states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
+    let ranges = if fold { case_fold_ranges(ranges) } else { ranges };
+    if negated {
+        states_from_ranges(complement_ranges(ranges, newline), states, frag_stack);
     }
-    let new_state = merge_non_match_states(states_vec);
-    frag_stack.push(states.len());
-    states.push(new_state);
-}
\ No newline at end of file
+    else {
+        states_from_ranges(normalize_ranges(ranges), states, frag_stack);
+    }
+}
+
+// '.' matches any character but the newline, unless `newline` is set
+pub fn create_dot_state(newline: bool, states: &mut Vec<State>, frag_stack: &mut Vec<usize>) {
+    states_from_ranges(complement_ranges(Vec::new(), newline), states, frag_stack);
+}
@@ -0,0 +1,64 @@
+use std::collections::HashSet;
+
+use super::state_utils::*;
+use super::unicode_tables::*;
+
+// The one-letter general categories, and the cased letters, are unions of the
+// two-letter categories in the tables
+const CATEGORY_GROUPS: [(&str, &str, &[&str]); 8] = [
+    ("L", "Letter", &["Lu", "Ll", "Lt", "Lm", "Lo"]),
+    ("LC", "Cased_Letter", &["Lu", "Ll", "Lt"]),
+    ("M", "Mark", &["Mn", "Mc", "Me"]),
+    ("N", "Number", &["Nd", "Nl", "No"]),
+    ("P", "Punctuation", &["Pc", "Pd", "Ps", "Pe", "Pi", "Pf", "Po"]),
+    ("S", "Symbol", &["Sm", "Sc", "Sk", "So"]),
+    ("Z", "Separator", &["Zs", "Zl", "Zp"]),
+    ("C", "Other", &["Cc", "Cf", "Cs", "Co", "Cn"]),
+];
+
+// Property names match loosely, ignoring case and underscores, so Greek,
+// greek and GREEK or Uppercase_Letter and uppercaseletter are all the same
+fn loose_eq(a: &str, b: &str) -> bool {
+    let a = a.chars().filter(|c| *c != '_').map(|c| c.to_ascii_lowercase());
+    let b = b.chars().filter(|c| *c != '_').map(|c| c.to_ascii_lowercase());
+    return a.eq(b);
+}
+
+fn table_ranges(table: PropertyTable, name: &str) -> Option<Vec<CharRange>> {
+    let (_, _, ranges) = table.iter().find(|(short, long, _)| loose_eq(short, name) || loose_eq(long, name))?;
+    return Some(ranges.iter().map(|(lo, hi)| (char::from_u32(*lo).unwrap(), char::from_u32(*hi).unwrap())).collect());
+}
+
+// The characters with the general category or script `name`, by short or
+// long name: L, Letter, Lu, Uppercase_Letter, Grek, Greek. None if no
+// property has that name.
+pub fn property_ranges(name: &str) -> Option<Vec<CharRange>> {
+    let ranges = match CATEGORY_GROUPS.iter().find(|(short, long, _)| loose_eq(short, name) || loose_eq(long, name)) {
+        Some((_, _, members)) => members.iter().flat_map(|m| table_ranges(GENERAL_CATEGORIES, m).unwrap_or_default()).collect(),
+        None => table_ranges(GENERAL_CATEGORIES, name).or_else(|| table_ranges(SCRIPTS, name))?,
+    };
+    // Complementing twice keeps only what a pattern can match, which drops
+    // the newline from Cc and Common
+    return Some(negate_ranges(negate_ranges(ranges)));
+}
+
+// Adds every character that case folds to the same character as one already
+// in `ranges`, so k also matches K and the Kelvin sign
+pub fn case_fold_ranges(ranges: Vec<CharRange>) -> Vec<CharRange> {
+    let ranges = normalize_ranges(ranges);
+    let contains = |c: u32| {
+        let i = ranges.partition_point(|(_, hi)| (*hi as u32) < c);
+        i < ranges.len() && ranges[i].0 as u32 <= c
+    };
+    let folds: HashSet<u32> = CASE_FOLDS.iter().filter(|(c, f)| contains(*c) || contains(*f)).map(|(_, f)| *f).collect();
+    let mut folded = ranges.clone();
+    for (c, f) in CASE_FOLDS.iter() {
+        if folds.contains(f) {
+            for c in [*c, *f] {
+                let c = char::from_u32(c).unwrap();
+                folded.push((c, c));
+            }
+        }
+    }
+    return normalize_ranges(folded);
+}
@@ -0,0 +1,178 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Something met while walking the operands: a file to search, a directory
+// whose symlinks lead back to one of its ancestors, or a path that could not
+// be read, which includes directories when not searching recursively
+#[derive(Debug)]
+pub enum Entry {
+    File(PathBuf),
+    Loop(PathBuf),
+    Error(PathBuf, io::Error),
+}
+
+// Walks the files named on the command line, `-` standing for standard input,
+// and with `recursive` the directories below them in name order. Symlinks
+// named on the command line are followed, and those met inside directories
+// only with `follow_symlinks`, as grep -r and -R do. The directories on the
+// way to each path are remembered so a link back to one of them is reported
+// instead of followed forever.
+pub struct Walker {
+    // Paths still to visit, last first, with the canonical paths of the
+    // directories they were found in
+    stack: Vec<(PathBuf, Rc<Vec<PathBuf>>)>,
+    recursive: bool,
+    follow_symlinks: bool,
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl Walker {
+    pub fn new(paths: &[String], recursive: bool, follow_symlinks: bool, include: &[String], exclude: &[String]) -> Walker {
+        let root = Rc::new(Vec::new());
+        let stack = paths.iter().rev().map(|p| (PathBuf::from(p), Rc::clone(&root))).collect();
+        Walker { stack, recursive, follow_symlinks, include: include.to_vec(), exclude: exclude.to_vec() }
+    }
+
+    // Whether --include and --exclude let a file through, by its name
+    fn selected(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return true,
+        };
+        if !self.include.is_empty() && !self.include.iter().any(|g| glob_match(g, &name)) {
+            return false;
+        }
+        return !self.exclude.iter().any(|g| glob_match(g, &name));
+    }
+
+    fn enter(&mut self, path: PathBuf, ancestors: &Rc<Vec<PathBuf>>) -> Option<Entry> {
+        let canonical = match fs::canonicalize(&path) {
+            Ok(canonical) => canonical,
+            Err(e) => return Some(Entry::Error(path, e)),
+        };
+        if ancestors.contains(&canonical) {
+            return Some(Entry::Loop(path));
+        }
+        let mut children: Vec<PathBuf> = match fs::read_dir(&path) {
+            Ok(entries) => entries.filter_map(|e| e.ok().map(|e| e.path())).collect(),
+            Err(e) => return Some(Entry::Error(path, e)),
+        };
+        children.sort();
+        let mut inside = ancestors.as_ref().clone();
+        inside.push(canonical);
+        let inside = Rc::new(inside);
+        for child in children.into_iter().rev() {
+            self.stack.push((child, Rc::clone(&inside)));
+        }
+        return None;
+    }
+}
+
+impl Iterator for Walker {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        while let Some((path, ancestors)) = self.stack.pop() {
+            // `-` is standard input, which is not on disk to look at
+            if path.as_os_str() == "-" {
+                return Some(Entry::File(path));
+            }
+            // Only operands have no ancestors
+            if !ancestors.is_empty() && !self.follow_symlinks && fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink()) {
+                continue;
+            }
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => return Some(Entry::Error(path, e)),
+            };
+            if metadata.is_dir() {
+                if !self.recursive {
+                    return Some(Entry::Error(path, io::Error::other("Is a directory")));
+                }
+                if let Some(entry) = self.enter(path, &ancestors) {
+                    return Some(entry);
+                }
+            }
+            else if self.selected(&path) {
+                return Some(Entry::File(path));
+            }
+        }
+        return None;
+    }
+}
+
+// Whether `name` matches the shell glob `pattern`: `*` matches any run of
+// characters, `?` any one, `[...]` one from a set that may hold ranges and is
+// negated by a leading `!` or `^`, and `\` makes the next character literal
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume if what follows the last `*` stops matching: just
+    // after the `*`, with it taking one more character of the name
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => match class_match(&pattern, p, name[n]) {
+                Some((true, next)) => Some(next),
+                Some((false, _)) => None,
+                None => (name[n] == '[').then_some(p + 1),
+            },
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == name[n]).then_some(p + 2),
+            Some(c) => (*c == name[n]).then_some(p + 1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {
+                p = next;
+                n += 1;
+            }
+            (None, Some((after, taken))) => {
+                star = Some((after, taken + 1));
+                p = after;
+                n = taken + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    return pattern[p..].iter().all(|c| *c == '*');
+}
+
+// Matches `c` against the bracket expression starting at `pattern[start]`,
+// giving whether it matched and the index after the closing `]`, or None if
+// the bracket is never closed and so is a literal `[`
+fn class_match(pattern: &[char], start: usize, c: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        // A `]` right after the opening bracket is a member, not the end
+        if pattern[i] == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        let lo = pattern[i];
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            matched |= lo <= c && c <= pattern[i + 2];
+            i += 3;
+        }
+        else {
+            matched |= lo == c;
+            i += 1;
+        }
+    }
+    return None;
+}
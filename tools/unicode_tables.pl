@@ -0,0 +1,94 @@
+#!/usr/bin/perl
+# Regenerates src/utils/unicode_tables.rs from the Unicode character database
+# that ships with Perl:
+#
+#   perl tools/unicode_tables.pl > src/utils/unicode_tables.rs
+use strict;
+use warnings;
+use Unicode::UCD qw(prop_values prop_value_aliases prop_invlist prop_invmap);
+
+# Inclusive (lo, hi) pairs from an inversion list, leaving out surrogates,
+# which are not chars
+sub ranges {
+    my @inv = prop_invlist($_[0]);
+    push @inv, 0x110000 if @inv % 2;
+    my @ranges;
+    while (my ($lo, $end) = splice(@inv, 0, 2)) {
+        my $hi = $end - 1;
+        if ($lo < 0xE000 && $hi >= 0xD800) {
+            push @ranges, [$lo, 0xD7FF] if $lo < 0xD800;
+            next if $hi < 0xE000;
+            $lo = 0xE000;
+        }
+        push @ranges, [$lo, $hi];
+    }
+    return @ranges;
+}
+
+sub table {
+    my ($name, $prop, @values) = @_;
+    print "pub const $name: PropertyTable = &[\n";
+    for my $value (sort @values) {
+        my ($short, $long) = prop_value_aliases($prop, $value);
+        my @ranges = ranges("$prop=$short");
+        next unless @ranges;
+        print "    (\"$short\", \"$long\", &[";
+        my $line = "";
+        for my $r (@ranges) {
+            my $pair = sprintf("(0x%X, 0x%X), ", @$r);
+            if (length($line) + length($pair) > 80) {
+                $line =~ s/ $//;
+                print "\n        $line";
+                $line = "";
+            }
+            $line .= $pair;
+        }
+        $line =~ s/, $//;
+        print "\n        $line\n    ]),\n";
+    }
+    print "];\n";
+}
+
+my $version = Unicode::UCD::UnicodeVersion();
+print <<"END";
+// Generated by tools/unicode_tables.pl from the Unicode $version character
+// database, do not edit by hand.
+//
+// Each property table entry is a short name, a long name and the sorted
+// inclusive ranges of code points with that property value.
+
+pub const UNICODE_VERSION: &str = "$version";
+
+pub type PropertyTable = &'static [(&'static str, &'static str, &'static [(u32, u32)])];
+
+END
+
+# Only the two-letter categories, the one-letter groups are unions of these
+table("GENERAL_CATEGORIES", "gc", grep { /^[A-Z][a-z]$/ } map { (prop_value_aliases("gc", $_))[0] } prop_values("gc"));
+print "\n";
+table("SCRIPTS", "sc", map { (prop_value_aliases("sc", $_))[0] } prop_values("sc"));
+
+# Every character whose simple case folding is another character, with that
+# character, sorted by the first
+my ($starts, $maps) = prop_invmap("Simple_Case_Folding");
+my @folds;
+for my $i (0 .. $#$starts - 1) {
+    next unless $maps->[$i];
+    for my $cp ($starts->[$i] .. $starts->[$i + 1] - 1) {
+        push @folds, [$cp, $maps->[$i] + $cp - $starts->[$i]];
+    }
+}
+print "\n// Each character whose simple case folding differs from it, and its folding\n";
+print "pub const CASE_FOLDS: &[(u32, u32)] = &[";
+my $line = "";
+for my $f (@folds) {
+    my $pair = sprintf("(0x%X, 0x%X), ", @$f);
+    if (length($line) + length($pair) > 84) {
+        $line =~ s/ $//;
+        print "\n    $line";
+        $line = "";
+    }
+    $line .= $pair;
+}
+$line =~ s/, $//;
+print "\n    $line\n];\n";